@@ -1,4 +1,6 @@
-use cassis::operation::{Operation, Trust};
+use cassis::log::{ConsistencyProof, Entry, Head, LineProof};
+use cassis::operation::{Hop, Operation, Transfer, Trust};
+use std::time::Duration;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
@@ -19,7 +21,8 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                     clap::Arg::new("since")
                         .long("since")
                         .short('s')
-                        .value_name("OPERATION-INDEX"),
+                        .value_name("OPERATION-INDEX")
+                        .help("start at this operation instead of the first"),
                 )
                 .arg(
                     clap::Arg::new("live")
@@ -50,38 +53,139 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                         .index(2),
                 ),
         )
+        .subcommand(
+            clap::Command::new("transfer")
+                .about("sends a payment to someone through the trust lines between you")
+                .arg(
+                    clap::Arg::new("secret_key")
+                        .long("key")
+                        .value_name("HEX-PRIVATE-KEY")
+                        .help("private key to use in the operation")
+                        .required(true),
+                )
+                .arg(
+                    clap::Arg::new("router_address")
+                        .long("router")
+                        .value_name("DOMAIN")
+                        .help("domain name of the cassis router used to find a route")
+                        .default_value("router.cassis.cash"),
+                )
+                .arg(
+                    clap::Arg::new("hop")
+                        .long("hop")
+                        .value_name("FROM:TO:AMOUNT")
+                        .help("use these hops (key indexes) instead of asking the router")
+                        .action(clap::ArgAction::Append),
+                )
+                .arg(
+                    clap::Arg::new("payee")
+                        .value_name("HEX-PUBLIC-KEY")
+                        .required(true)
+                        .index(1),
+                )
+                .arg(
+                    clap::Arg::new("amount")
+                        .value_name("SATOSHIS")
+                        .required(true)
+                        .index(2),
+                ),
+        )
+        .subcommand(
+            clap::Command::new("audit")
+                .about("keeps checking that the registry only ever appends to its log")
+                .arg(
+                    clap::Arg::new("registry_key")
+                        .long("registry-key")
+                        .value_name("HEX-PUBLIC-KEY")
+                        .help("public key the registry signs its log with")
+                        .default_value(
+                            "46d44c5e71dbbb5b59d97e1aa887d9bdd05ed052178a0b588f99d089e61dfd20",
+                        ),
+                )
+                .arg(
+                    clap::Arg::new("interval")
+                        .long("interval")
+                        .value_name("SECONDS")
+                        .help("how long to wait between checks")
+                        .default_value("60"),
+                ),
+        )
+        .subcommand(
+            clap::Command::new("line")
+                .about("shows the line between two keys as committed to by the registry")
+                .arg(
+                    clap::Arg::new("registry_key")
+                        .long("registry-key")
+                        .value_name("HEX-PUBLIC-KEY")
+                        .help("public key the registry signs its log with")
+                        .default_value(
+                            "46d44c5e71dbbb5b59d97e1aa887d9bdd05ed052178a0b588f99d089e61dfd20",
+                        ),
+                )
+                .arg(
+                    clap::Arg::new("a")
+                        .value_name("KEY-INDEX")
+                        .required(true)
+                        .index(1),
+                )
+                .arg(
+                    clap::Arg::new("b")
+                        .value_name("KEY-INDEX")
+                        .required(true)
+                        .index(2),
+                ),
+        )
         .get_matches();
 
-    let host = matches.get_one::<String>("registry_address").unwrap();
-    let base = if host.starts_with("localhost") {
-        format!("http://{}", host)
-    } else {
-        format!("https://{}", host)
-    };
+    let base = base_url(matches.get_one::<String>("registry_address").unwrap());
 
     let client = reqwest::Client::new();
 
     if let Some(matches) = matches.subcommand_matches("log") {
         let live = matches.get_flag("live");
-        let since = matches.get_one::<String>("since");
+        let since = matches.get_one::<String>("since").map(|s| {
+            s.parse::<u32>()
+                .expect("operation index is not a valid integer")
+        });
+
+        // the registry gives what comes after an index, so to start at `since` we ask for what
+        // comes after the one before it
+        let mut after = since.and_then(|since| since.checked_sub(1));
 
-        let mut req = client.get(format!("{}/log", base));
         if live {
-            req = req.query(&[("live", "true")]);
-        }
-        match since {
-            Some(s) => {
-                req = req.query(&[("since", s)]);
+            let mut req = client
+                .get(format!("{}/log", base))
+                .query(&[("live", "true")]);
+            if let Some(after) = after {
+                req = req.query(&[("after", after)]);
             }
-            None => {}
-        }
 
-        let mut response = req.send().await?;
-        while let Some(chunk) = response.chunk().await? {
-            print!(
-                "{}",
-                String::from_utf8(chunk.to_vec()).unwrap_or("<broken-data>".to_string())
-            )
+            let mut response = req.send().await?.error_for_status()?;
+            while let Some(chunk) = response.chunk().await? {
+                print!(
+                    "{}",
+                    String::from_utf8(chunk.to_vec()).unwrap_or("<broken-data>".to_string())
+                )
+            }
+        } else {
+            loop {
+                let mut req = client.get(format!("{}/log", base));
+                if let Some(after) = after {
+                    req = req.query(&[("after", after)]);
+                }
+
+                let page: serde_json::Value =
+                    serde_json::from_str(&req.send().await?.error_for_status()?.text().await?)?;
+                let entries: Vec<Entry> = serde_json::from_value(page["entries"].clone())?;
+                for entry in entries {
+                    println!("{}", serde_json::to_string(&entry)?);
+                }
+
+                match page["next"].as_u64() {
+                    Some(next) => after = Some(next as u32),
+                    None => break,
+                }
+            }
         }
     } else if let Some(matches) = matches.subcommand_matches("trust") {
         let sk = cassis::SecretKey::from_hex(matches.get_one::<String>("secret_key").unwrap())
@@ -95,15 +199,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             .expect("amount is not a valid integer");
 
         // get our key index from server
-        let from = client
-            .get(format!("{}/idx/{}", base, sk.public()))
-            .send()
-            .await?
-            .error_for_status()?
-            .text()
-            .await?
-            .parse::<u32>()
-            .expect("response from /key call is not a valid integer");
+        let from = get_key_index(&client, &base, &sk.public()).await?;
 
         // build trust operation
         let data = Operation::Trust(Trust::new(sk, from, to, amount));
@@ -118,7 +214,268 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             .error_for_status()?;
 
         println!("success!");
+    } else if let Some(matches) = matches.subcommand_matches("transfer") {
+        let sk = cassis::SecretKey::from_hex(matches.get_one::<String>("secret_key").unwrap())
+            .expect("invalid private key");
+        let payee = cassis::PublicKey::from_hex(matches.get_one::<String>("payee").unwrap())
+            .expect("invalid payee public key");
+        let amount = matches
+            .get_one::<String>("amount")
+            .unwrap()
+            .parse::<u32>()
+            .expect("amount is not a valid integer");
+
+        // get both key indexes from server
+        let from = get_key_index(&client, &base, &sk.public()).await?;
+        let to = get_key_index(&client, &base, &payee).await?;
+
+        let hops: Vec<Hop> = match matches.get_many::<String>("hop") {
+            Some(explicit) => explicit
+                .map(|spec| parse_hop(spec).expect("hop must be FROM:TO:AMOUNT"))
+                .collect(),
+            None => {
+                let router = base_url(matches.get_one::<String>("router_address").unwrap());
+                find_hops(&client, &router, from, to, amount).await?
+            }
+        };
+
+        // make sure the hops deliver exactly what was asked
+        let received: i64 = hops
+            .iter()
+            .map(|hop| match (hop.to == to, hop.from == to) {
+                (true, _) => hop.amount as i64,
+                (_, true) => -(hop.amount as i64),
+                _ => 0,
+            })
+            .sum();
+        if received != amount as i64 {
+            return Err(format!("hops deliver {} to the payee, not {}", received, amount).into());
+        }
+
+        // build and sign the transfer operation
+        let mut builder = Transfer::builder();
+        builder.add_hops(hops)?;
+        builder.sign(&sk, from)?;
+        // the registry gave us `from` for our own key, and we are the only one signing
+        let transfer = builder.finalize(|idx| (idx == from).then(|| sk.public()))?;
+        let chain = transfer
+            .hops
+            .iter()
+            .map(|hop| hop.to_string())
+            .collect::<Vec<String>>()
+            .join(" ");
+        let data = Operation::Transfer(transfer);
+
+        // send to server
+        let _ = client
+            .post(format!("{}/append", base))
+            .body(serde_json::to_string(&data)?)
+            .header("Content-Type", "application/json")
+            .send()
+            .await?
+            .error_for_status()?;
+
+        println!("{}", chain);
+    } else if let Some(matches) = matches.subcommand_matches("audit") {
+        let registry_key =
+            cassis::PublicKey::from_hex(matches.get_one::<String>("registry_key").unwrap())
+                .expect("invalid registry public key");
+        let interval = matches
+            .get_one::<String>("interval")
+            .unwrap()
+            .parse::<u64>()
+            .expect("interval is not a valid integer");
+
+        let mut last: Option<Head> = None;
+        loop {
+            // failing to reach the registry is not proof of anything, so we just try again later
+            match fetch_head(&client, &base).await {
+                Err(err) => eprintln!("failed to fetch head: {}", err),
+                Ok(None) => println!("log is empty"),
+                Ok(Some(head)) => {
+                    if let Err(err) =
+                        audit(&client, &base, &registry_key, last.as_ref(), &head).await
+                    {
+                        eprintln!("ALERT: {}", err);
+                        return Err(err);
+                    }
+                    println!("{} {} ok", head.idx, hex::encode(head.root));
+                    last = Some(head);
+                }
+            }
+
+            tokio::time::sleep(Duration::from_secs(interval)).await;
+        }
+    } else if let Some(matches) = matches.subcommand_matches("line") {
+        let registry_key =
+            cassis::PublicKey::from_hex(matches.get_one::<String>("registry_key").unwrap())
+                .expect("invalid registry public key");
+        let [a, b] = ["a", "b"].map(|arg| {
+            matches
+                .get_one::<String>(arg)
+                .unwrap()
+                .parse::<u32>()
+                .expect("key index is not a valid integer")
+        });
+
+        let proof: LineProof = serde_json::from_str(
+            &client
+                .get(format!("{}/line/{}/{}/proof", base, a, b))
+                .send()
+                .await?
+                .error_for_status()?
+                .text()
+                .await?,
+        )?;
+        proof.verify(&registry_key)?;
+        if cassis::state::Line::build_key(proof.line.peers.0, proof.line.peers.1)
+            != cassis::state::Line::build_key(a, b)
+        {
+            return Err(format!("registry sent the wrong line for {} and {}", a, b).into());
+        }
+
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&serde_json::json!({
+                "line": proof.line,
+                "at": proof.head.idx,
+            }))?
+        );
+    }
+
+    Ok(())
+}
+
+fn base_url(host: &str) -> String {
+    if host.starts_with("localhost") {
+        format!("http://{}", host)
+    } else {
+        format!("https://{}", host)
     }
+}
+
+async fn get_key_index(
+    client: &reqwest::Client,
+    base: &str,
+    pubkey: &cassis::PublicKey,
+) -> Result<u32, Box<dyn std::error::Error>> {
+    let idx = client
+        .get(format!("{}/idx/{}", base, pubkey))
+        .send()
+        .await?
+        .error_for_status()?
+        .text()
+        .await?
+        .parse::<u32>()
+        .expect("response from /idx call is not a valid integer");
+    Ok(idx)
+}
+
+async fn fetch_head(
+    client: &reqwest::Client,
+    base: &str,
+) -> Result<Option<Head>, Box<dyn std::error::Error>> {
+    let response = client.get(format!("{}/head", base)).send().await?;
+    if response.status() == reqwest::StatusCode::NOT_FOUND {
+        return Ok(None);
+    }
+    Ok(Some(serde_json::from_str(
+        &response.error_for_status()?.text().await?,
+    )?))
+}
+
+// checks that the new head was signed by the registry and that the log it sums up is the log of
+// the last head we saw with some more entries appended
+async fn audit(
+    client: &reqwest::Client,
+    base: &str,
+    registry_key: &cassis::PublicKey,
+    last: Option<&Head>,
+    head: &Head,
+) -> Result<(), Box<dyn std::error::Error>> {
+    head.verify(registry_key)
+        .map_err(|err| format!("head {} not signed by the registry: {}", head.idx, err))?;
+
+    let Some(last) = last else {
+        return Ok(());
+    };
+
+    if head.idx < last.idx {
+        return Err(format!("log went back from {} to {}", last.idx, head.idx).into());
+    }
+    if head.idx == last.idx {
+        if head.hash != last.hash || head.root != last.root || head.state_root != last.state_root {
+            return Err(format!("registry signed two different logs at {}", head.idx).into());
+        }
+        return Ok(());
+    }
+
+    let proof: ConsistencyProof = serde_json::from_str(
+        &client
+            .get(format!("{}/consistency", base))
+            .query(&[("from", last.idx + 1), ("to", head.idx + 1)])
+            .send()
+            .await?
+            .error_for_status()?
+            .text()
+            .await?,
+    )?;
+    proof.verify(last, head, registry_key)?;
 
     Ok(())
 }
+
+fn parse_hop(spec: &str) -> Option<Hop> {
+    let mut parts = spec.split(':').map(|part| part.parse::<u32>());
+    let hop = Hop {
+        from: parts.next()?.ok()?,
+        to: parts.next()?.ok()?,
+        amount: parts.next()?.ok()?,
+    };
+    match parts.next() {
+        None => Some(hop),
+        Some(_) => None,
+    }
+}
+
+// asks the router for a single route that can carry everything and, if there is none, for the
+// payment split across many routes merged into a single list of hops
+async fn find_hops(
+    client: &reqwest::Client,
+    router: &str,
+    from: u32,
+    to: u32,
+    amount: u32,
+) -> Result<Vec<Hop>, Box<dyn std::error::Error>> {
+    let query = [
+        ("from", from.to_string()),
+        ("to", to.to_string()),
+        ("amount", amount.to_string()),
+    ];
+
+    let response = client
+        .get(format!("{}/route", router))
+        .query(&query)
+        .query(&[("max", "1")])
+        .send()
+        .await?;
+    if response.status() != reqwest::StatusCode::NOT_FOUND {
+        let routes: Vec<Vec<Hop>> =
+            serde_json::from_str(&response.error_for_status()?.text().await?)?;
+        if let Some(route) = routes.into_iter().next() {
+            return Ok(route);
+        }
+    }
+
+    let split: serde_json::Value = serde_json::from_str(
+        &client
+            .get(format!("{}/split", router))
+            .query(&query)
+            .send()
+            .await?
+            .error_for_status()?
+            .text()
+            .await?,
+    )?;
+    Ok(serde_json::from_value(split["hops"].clone())?)
+}
@@ -34,7 +34,7 @@ impl SecretKey {
     }
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct PublicKey(pub(crate) secp256k1::XOnlyPublicKey);
 
 impl fmt::Display for PublicKey {
@@ -47,8 +47,11 @@ impl PublicKey {
     pub fn from_hex(s: &String) -> Result<Self, KeyParseError> {
         let mut pk_slice = [0u8; 32];
         hex::decode_to_slice(s, &mut pk_slice).map_err(|_| KeyParseError {})?;
-        let keypair = secp256k1::XOnlyPublicKey::from_slice(pk_slice.as_slice())
-            .map_err(|_| KeyParseError {})?;
+        PublicKey::from_slice(&pk_slice)
+    }
+
+    pub fn from_slice(data: &[u8]) -> Result<Self, KeyParseError> {
+        let keypair = secp256k1::XOnlyPublicKey::from_slice(data).map_err(|_| KeyParseError {})?;
         Ok(PublicKey(keypair))
     }
 
@@ -1,6 +1,10 @@
 pub mod key;
+pub mod log;
+pub mod merkle;
 pub mod operation;
 pub mod state;
+#[cfg(feature = "test-utils")]
+pub mod test_utils;
 
 pub use crate::key::{PublicKey, SecretKey};
 pub use operation::*;
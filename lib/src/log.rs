@@ -0,0 +1,218 @@
+use anyhow::anyhow;
+use byteorder::{ByteOrder, LE};
+use secp256k1::hashes::{sha256, Hash};
+
+use crate::key::{PublicKey, SecretKey};
+use crate::merkle;
+use crate::operation::Operation;
+use crate::state::{root, Line};
+
+// what comes before the first entry in the chain
+pub const GENESIS: [u8; 32] = [0; 32];
+
+// hash of a single entry, that is, of an operation as it is written in the log
+pub fn entry_hash(op: &Operation) -> [u8; 32] {
+    let mut buf = vec![0; op.size()];
+    op.write_serialized(&mut buf);
+    sha256::Hash::hash(&buf).to_byte_array()
+}
+
+// each entry commits to everything before it: the chain hash at some index is the hash of the
+// previous chain hash followed by the hash of the entry at that index
+pub fn chain_hash(previous: &[u8; 32], entry_hash: &[u8; 32]) -> [u8; 32] {
+    let mut concat = [0u8; 64];
+    concat[0..32].copy_from_slice(previous);
+    concat[32..64].copy_from_slice(entry_hash);
+    sha256::Hash::hash(&concat).to_byte_array()
+}
+
+// computes the chain hash we get after appending all these operations to `previous` (which is
+// `GENESIS` when starting from the beginning of the log)
+pub fn chain<'a>(previous: [u8; 32], ops: impl IntoIterator<Item = &'a Operation>) -> [u8; 32] {
+    ops.into_iter()
+        .fold(previous, |hash, op| chain_hash(&hash, &entry_hash(op)))
+}
+
+// an operation together with its position in the log and the chain hash at that position, so
+// whoever reads entries one after the other can check that none were changed, missed or repeated
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct Entry {
+    pub idx: u32,
+    #[serde(with = "hex::serde")]
+    pub hash: [u8; 32],
+    pub op: Operation,
+}
+
+impl Entry {
+    // whether this comes right after the entry with chain hash `previous` (`GENESIS` for the first)
+    pub fn follows(&self, previous: &[u8; 32]) -> bool {
+        chain_hash(previous, &entry_hash(&self.op)) == self.hash
+    }
+}
+
+// the latest entry in the log as attested by the registry. a registry that signs two different
+// hashes for the same index, or a hash that doesn't chain up to one it signed before, has forked
+// or rewritten its history. `root` is the merkle root of all entries up to and including `idx` and
+// `state_root` is the root of the state we get after applying them.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct Head {
+    pub idx: u32,
+    #[serde(with = "hex::serde")]
+    pub hash: [u8; 32],
+    #[serde(with = "hex::serde")]
+    pub root: [u8; 32],
+    #[serde(with = "hex::serde")]
+    pub state_root: [u8; 32],
+    #[serde(with = "hex::serde")]
+    pub sig: [u8; 64],
+}
+
+impl Head {
+    const TAG: u8 = b'h';
+
+    pub fn sign(
+        secret_key: &SecretKey,
+        idx: u32,
+        hash: [u8; 32],
+        root: [u8; 32],
+        state_root: [u8; 32],
+    ) -> Self {
+        let mut head = Head {
+            idx,
+            hash,
+            root,
+            state_root,
+            sig: [0; 64],
+        };
+        head.sig = secret_key.sign(head.sighash());
+        head
+    }
+
+    // [tag][idx][hash][root][state_root]
+    pub fn sighash(&self) -> [u8; 32] {
+        let mut buf = [0u8; 101];
+        buf[0] = Head::TAG;
+        LE::write_u32(&mut buf[1..5], self.idx);
+        buf[5..37].copy_from_slice(&self.hash);
+        buf[37..69].copy_from_slice(&self.root);
+        buf[69..101].copy_from_slice(&self.state_root);
+        sha256::Hash::hash(&buf).to_byte_array()
+    }
+
+    pub fn verify(&self, registry_key: &PublicKey) -> Result<(), secp256k1::Error> {
+        registry_key.verify(&self.sig, &self.sighash())
+    }
+}
+
+// shows that the operation at `idx` is part of the log summed up by a signed head
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct InclusionProof {
+    pub idx: u32,
+    #[serde(with = "merkle::hex_list")]
+    pub path: Vec<[u8; 32]>,
+    pub head: Head,
+}
+
+impl InclusionProof {
+    pub fn verify(&self, op: &Operation, registry_key: &PublicKey) -> Result<(), anyhow::Error> {
+        self.head
+            .verify(registry_key)
+            .map_err(|err| anyhow!("head not signed by the registry: {}", err))?;
+
+        if !merkle::verify_inclusion(
+            &entry_hash(op),
+            self.idx as u64,
+            self.head.idx as u64 + 1,
+            &self.path,
+            &self.head.root,
+        ) {
+            return Err(anyhow!(
+                "operation is not at {} in the log up to {}",
+                self.idx,
+                self.head.idx
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+// shows that the log with `to` entries is the log with `from` entries plus some more, that is,
+// that nothing was changed in between
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct ConsistencyProof {
+    pub from: u64,
+    pub to: u64,
+    #[serde(with = "merkle::hex_list")]
+    pub path: Vec<[u8; 32]>,
+}
+
+impl ConsistencyProof {
+    // checks the proof between two heads signed by the registry
+    pub fn verify(
+        &self,
+        old: &Head,
+        new: &Head,
+        registry_key: &PublicKey,
+    ) -> Result<(), anyhow::Error> {
+        for head in [old, new] {
+            head.verify(registry_key)
+                .map_err(|err| anyhow!("head {} not signed by the registry: {}", head.idx, err))?;
+        }
+
+        if self.from != old.idx as u64 + 1 || self.to != new.idx as u64 + 1 {
+            return Err(anyhow!(
+                "proof is from {} to {}, not between heads {} and {}",
+                self.from,
+                self.to,
+                old.idx,
+                new.idx
+            ));
+        }
+
+        if !merkle::verify_consistency(self.from, self.to, &self.path, &old.root, &new.root) {
+            return Err(anyhow!(
+                "log at {} doesn't extend the log at {}",
+                new.idx,
+                old.idx
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+// shows that `line` is the line between its two peers in the state signed by the registry in
+// `head`. `keys_root` is only there so the state root can be recomputed.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct LineProof {
+    pub line: Line,
+    #[serde(with = "hex::serde")]
+    pub keys_root: [u8; 32],
+    #[serde(with = "merkle::hex_list")]
+    pub path: Vec<[u8; 32]>,
+    pub head: Head,
+}
+
+impl LineProof {
+    pub fn verify(&self, registry_key: &PublicKey) -> Result<(), anyhow::Error> {
+        self.head
+            .verify(registry_key)
+            .map_err(|err| anyhow!("head not signed by the registry: {}", err))?;
+
+        // the path takes us to the lines root, which together with the keys root must give the
+        // state root that was signed
+        let lines_root = root::root_from_proof(&self.line, &self.path)
+            .ok_or_else(|| anyhow!("not a valid line proof"))?;
+        if root::state_root(&self.keys_root, &lines_root) != self.head.state_root {
+            return Err(anyhow!(
+                "line between {} and {} is not in the state at {}",
+                self.line.peers.0,
+                self.line.peers.1,
+                self.head.idx
+            ));
+        }
+
+        Ok(())
+    }
+}
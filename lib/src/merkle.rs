@@ -0,0 +1,269 @@
+use secp256k1::hashes::{sha256, Hash, HashEngine};
+
+// an append-only merkle tree over the entry hashes of the log, as described in RFC 6962.
+// leaves and inner nodes are hashed with different prefixes so one can't pass for the other.
+pub fn leaf_hash(entry_hash: &[u8; 32]) -> [u8; 32] {
+    let mut engine = sha256::Hash::engine();
+    engine.input(&[0x00]);
+    engine.input(entry_hash);
+    sha256::Hash::from_engine(engine).to_byte_array()
+}
+
+pub fn node_hash(left: &[u8; 32], right: &[u8; 32]) -> [u8; 32] {
+    let mut engine = sha256::Hash::engine();
+    engine.input(&[0x01]);
+    engine.input(left);
+    engine.input(right);
+    sha256::Hash::from_engine(engine).to_byte_array()
+}
+
+// the largest power of two smaller than `n`, which is where a tree of size `n` is split
+fn split(n: u64) -> u64 {
+    1 << (63 - (n - 1).leading_zeros())
+}
+
+// all nodes of all perfect subtrees, so any root or proof takes a logarithmic number of hashes.
+// `levels[0]` has the leaf hashes, `levels[k][i]` is the root of the leaves `i * 2^k` up to
+// `(i + 1) * 2^k`.
+#[derive(Debug, Clone, Default)]
+pub struct Tree {
+    levels: Vec<Vec<[u8; 32]>>,
+}
+
+impl Tree {
+    pub fn new() -> Self {
+        Tree::default()
+    }
+
+    pub fn len(&self) -> u64 {
+        self.levels.first().map_or(0, |leaves| leaves.len() as u64)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn push(&mut self, entry_hash: &[u8; 32]) {
+        let mut hash = leaf_hash(entry_hash);
+        let mut level = 0;
+        loop {
+            if self.levels.len() == level {
+                self.levels.push(Vec::new());
+            }
+            self.levels[level].push(hash);
+
+            // every time a level gets an even number of nodes the last two are joined above
+            let nodes = &self.levels[level];
+            if nodes.len() % 2 == 1 {
+                break;
+            }
+            hash = node_hash(&nodes[nodes.len() - 2], &nodes[nodes.len() - 1]);
+            level += 1;
+        }
+    }
+
+    // root of the tree as it was when it had `size` leaves
+    pub fn root_at(&self, size: u64) -> Option<[u8; 32]> {
+        match size {
+            0 => Some(sha256::Hash::hash(&[]).to_byte_array()),
+            _ if size > self.len() => None,
+            _ => Some(self.subtree(0, size)),
+        }
+    }
+
+    pub fn root(&self) -> [u8; 32] {
+        self.root_at(self.len()).unwrap()
+    }
+
+    // the hashes needed to go from leaf `idx` to the root of the tree with `size` leaves
+    pub fn inclusion_proof(&self, idx: u64, size: u64) -> Option<Vec<[u8; 32]>> {
+        if idx >= size || size > self.len() {
+            return None;
+        }
+
+        let mut proof = Vec::new();
+        self.path(idx, 0, size, &mut proof);
+        Some(proof)
+    }
+
+    fn path(&self, idx: u64, start: u64, len: u64, proof: &mut Vec<[u8; 32]>) {
+        if len == 1 {
+            return;
+        }
+
+        let k = split(len);
+        if idx < start + k {
+            self.path(idx, start, k, proof);
+            proof.push(self.subtree(start + k, len - k));
+        } else {
+            self.path(idx, start + k, len - k, proof);
+            proof.push(self.subtree(start, k));
+        }
+    }
+
+    // the hashes needed to show that the tree with `to` leaves is the tree with `from` leaves with
+    // some more appended to it
+    pub fn consistency_proof(&self, from: u64, to: u64) -> Option<Vec<[u8; 32]>> {
+        if from == 0 || from > to || to > self.len() {
+            return None;
+        }
+
+        let mut proof = Vec::new();
+        self.subproof(from, 0, to, true, &mut proof);
+        Some(proof)
+    }
+
+    // `whole` tells if the first `from` leaves of this subtree are a subtree of the old tree
+    // whose root the verifier already knows
+    fn subproof(&self, from: u64, start: u64, len: u64, whole: bool, proof: &mut Vec<[u8; 32]>) {
+        if from == len {
+            if !whole {
+                proof.push(self.subtree(start, len));
+            }
+            return;
+        }
+
+        let k = split(len);
+        if from <= k {
+            self.subproof(from, start, k, whole, proof);
+            proof.push(self.subtree(start + k, len - k));
+        } else {
+            self.subproof(from - k, start + k, len - k, false, proof);
+            proof.push(self.subtree(start, k));
+        }
+    }
+
+    // root of the leaves from `start` up to `start + len`
+    fn subtree(&self, start: u64, len: u64) -> [u8; 32] {
+        if len.is_power_of_two() && start.is_multiple_of(len) {
+            let level = len.trailing_zeros() as usize;
+            return self.levels[level][(start / len) as usize];
+        }
+
+        let k = split(len);
+        node_hash(&self.subtree(start, k), &self.subtree(start + k, len - k))
+    }
+}
+
+// checks that the entry with this hash is at `idx` in the tree with `size` leaves and this root
+pub fn verify_inclusion(
+    entry_hash: &[u8; 32],
+    idx: u64,
+    size: u64,
+    proof: &[[u8; 32]],
+    root: &[u8; 32],
+) -> bool {
+    if idx >= size {
+        return false;
+    }
+
+    // walk up from the leaf, the bits of `idx` tell on which side each sibling is, except where
+    // the tree is not perfect and the node is promoted without a sibling
+    let mut node = idx;
+    let mut last = size - 1;
+    let mut hash = leaf_hash(entry_hash);
+    for sibling in proof {
+        if last == 0 {
+            return false;
+        }
+
+        if node & 1 == 1 || node == last {
+            hash = node_hash(sibling, &hash);
+            while node & 1 == 0 && node != 0 {
+                node >>= 1;
+                last >>= 1;
+            }
+        } else {
+            hash = node_hash(&hash, sibling);
+        }
+        node >>= 1;
+        last >>= 1;
+    }
+
+    last == 0 && hash == *root
+}
+
+// checks that the tree with `to` leaves and `new_root` contains the tree with `from` leaves and
+// `old_root` unchanged as its beginning
+pub fn verify_consistency(
+    from: u64,
+    to: u64,
+    proof: &[[u8; 32]],
+    old_root: &[u8; 32],
+    new_root: &[u8; 32],
+) -> bool {
+    if from == 0 || from > to {
+        return false;
+    }
+    if from == to {
+        return proof.is_empty() && old_root == new_root;
+    }
+
+    // when the old tree is perfect its root is where the proof starts, otherwise that is in it
+    let mut path = proof.iter();
+    let first = match from.is_power_of_two() {
+        true => *old_root,
+        false => match path.next() {
+            Some(hash) => *hash,
+            None => return false,
+        },
+    };
+
+    // like in the inclusion proof we walk up from the last leaf of the old tree, but computing
+    // both roots at the same time
+    let mut node = from - 1;
+    let mut last = to - 1;
+    while node & 1 == 1 {
+        node >>= 1;
+        last >>= 1;
+    }
+
+    let mut old_hash = first;
+    let mut new_hash = first;
+    for sibling in path {
+        if last == 0 {
+            return false;
+        }
+
+        if node & 1 == 1 || node == last {
+            old_hash = node_hash(sibling, &old_hash);
+            new_hash = node_hash(sibling, &new_hash);
+            while node & 1 == 0 && node != 0 {
+                node >>= 1;
+                last >>= 1;
+            }
+        } else {
+            new_hash = node_hash(&new_hash, sibling);
+        }
+        node >>= 1;
+        last >>= 1;
+    }
+
+    last == 0 && old_hash == *old_root && new_hash == *new_root
+}
+
+// serializes a list of hashes as a list of hex strings
+pub(crate) mod hex_list {
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S>(hashes: &[[u8; 32]], serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.collect_seq(hashes.iter().map(hex::encode))
+    }
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<Vec<[u8; 32]>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        Vec::<String>::deserialize(deserializer)?
+            .iter()
+            .map(|s| {
+                let mut hash = [0u8; 32];
+                hex::decode_to_slice(s, &mut hash).map_err(serde::de::Error::custom)?;
+                Ok(hash)
+            })
+            .collect()
+    }
+}
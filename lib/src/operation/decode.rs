@@ -0,0 +1,119 @@
+use byteorder::{ByteOrder, LE};
+use std::fmt;
+
+use crate::key::PublicKey;
+
+// why a buffer couldn't be decoded into an operation, `offset` is where in the buffer it happened
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DecodeError {
+    // the buffer ended while reading at `offset`, `needed` bytes short
+    Truncated { offset: usize, needed: usize },
+    // the first byte isn't one of the operation tags we know
+    UnknownTag { offset: usize, tag: u8 },
+    // 32 bytes that don't make a valid x-only public key
+    InvalidPublicKey { offset: usize },
+    // a transfer can't be signed by more peers than those in its hops
+    TooManySignatures { offset: usize, nsigs: usize },
+    // the operation ended before the buffer did
+    TrailingBytes { offset: usize, extra: usize },
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DecodeError::Truncated { offset, needed } => {
+                write!(
+                    f,
+                    "buffer ended reading at {}, needed {} more bytes",
+                    offset, needed
+                )
+            }
+            DecodeError::UnknownTag { offset, tag } => {
+                write!(f, "unknown operation tag {:#04x} at {}", tag, offset)
+            }
+            DecodeError::InvalidPublicKey { offset } => {
+                write!(f, "invalid public key at {}", offset)
+            }
+            DecodeError::TooManySignatures { offset, nsigs } => {
+                write!(
+                    f,
+                    "{} signatures at {} is more than there are peers",
+                    nsigs, offset
+                )
+            }
+            DecodeError::TrailingBytes { offset, extra } => {
+                write!(
+                    f,
+                    "{} unexpected bytes after the operation at {}",
+                    extra, offset
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+// reads a buffer from start to end, failing instead of panicking when it is too short
+pub(crate) struct Reader<'a> {
+    buf: &'a [u8],
+    pub(crate) offset: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub(crate) fn new(buf: &'a [u8]) -> Self {
+        Reader { buf, offset: 0 }
+    }
+
+    pub(crate) fn take(&mut self, len: usize) -> Result<&'a [u8], DecodeError> {
+        let remaining = self.buf.len() - self.offset;
+        if len > remaining {
+            return Err(DecodeError::Truncated {
+                offset: self.offset,
+                needed: len - remaining,
+            });
+        }
+
+        let slice = &self.buf[self.offset..self.offset + len];
+        self.offset += len;
+        Ok(slice)
+    }
+
+    pub(crate) fn u8(&mut self) -> Result<u8, DecodeError> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub(crate) fn u32(&mut self) -> Result<u32, DecodeError> {
+        Ok(LE::read_u32(self.take(4)?))
+    }
+
+    pub(crate) fn tag(&mut self, expected: u8) -> Result<(), DecodeError> {
+        let offset = self.offset;
+        match self.u8()? {
+            tag if tag == expected => Ok(()),
+            tag => Err(DecodeError::UnknownTag { offset, tag }),
+        }
+    }
+
+    pub(crate) fn public_key(&mut self) -> Result<PublicKey, DecodeError> {
+        let offset = self.offset;
+        secp256k1::XOnlyPublicKey::from_slice(self.take(32)?)
+            .map(PublicKey)
+            .map_err(|_| DecodeError::InvalidPublicKey { offset })
+    }
+
+    pub(crate) fn signature(&mut self) -> Result<[u8; 64], DecodeError> {
+        Ok(self.take(64)?.try_into().unwrap())
+    }
+
+    // everything must have been read
+    pub(crate) fn finish(self) -> Result<(), DecodeError> {
+        if self.offset < self.buf.len() {
+            return Err(DecodeError::TrailingBytes {
+                offset: self.offset,
+                extra: self.buf.len() - self.offset,
+            });
+        }
+        Ok(())
+    }
+}
@@ -1,13 +1,15 @@
 use secp256k1::hashes::{sha256, Hash};
 use std::fmt;
 
+mod decode;
 mod transfer;
 mod trust;
 
-pub use transfer::Transfer;
+pub use decode::DecodeError;
+pub use transfer::{Hop, PeerSig, Transfer, TransferBuilder};
 pub use trust::Trust;
 
-#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
+#[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
 #[serde(tag = "tag")]
 pub enum Operation {
     #[serde(rename = "t")]
@@ -21,19 +23,22 @@ pub enum Operation {
 pub trait OperationOps {
     const TAG: u8;
 
+    // writes the whole operation, signatures included, into a buffer of `size()` bytes.
+    // `TryFrom<&[u8]>` reads it back.
     fn write_serialized(&self, buf: &mut [u8]);
 
+    // writes just the part that is signed into a buffer of `size_nosig()` bytes
+    fn write_nosig(&self, buf: &mut [u8]);
+
     fn size(&self) -> usize;
     fn size_nosig(&self) -> usize;
 
     fn sighash(&self) -> [u8; 32] {
         let mut nosig = vec![0u8; self.size_nosig()];
-        self.write_serialized(&mut nosig.as_mut_slice());
+        self.write_nosig(nosig.as_mut_slice());
         let digest = sha256::Hash::hash(&nosig);
         digest.to_byte_array()
     }
-
-    fn deserialize(buf: &[u8]) -> Self;
 }
 
 impl fmt::Display for Operation {
@@ -70,12 +75,20 @@ impl Operation {
             Operation::Unknown => {}
         }
     }
+}
 
-    pub fn deserialize(buf: &[u8]) -> Self {
-        match buf[0] {
-            Transfer::TAG => Operation::Transfer(Transfer::deserialize(buf)),
-            Trust::TAG => Operation::Trust(Trust::deserialize(buf)),
-            _ => Operation::Unknown,
+impl TryFrom<&[u8]> for Operation {
+    type Error = DecodeError;
+
+    fn try_from(buf: &[u8]) -> Result<Self, Self::Error> {
+        match buf.first() {
+            Some(&Transfer::TAG) => Transfer::try_from(buf).map(Operation::Transfer),
+            Some(&Trust::TAG) => Trust::try_from(buf).map(Operation::Trust),
+            Some(&tag) => Err(DecodeError::UnknownTag { offset: 0, tag }),
+            None => Err(DecodeError::Truncated {
+                offset: 0,
+                needed: 1,
+            }),
         }
     }
 }
@@ -94,11 +107,9 @@ impl redb::Value for Operation {
         Self: 'a,
         Self: 'b,
     {
-        match op {
-            Operation::Transfer(t) => Transfer::as_bytes(t),
-            Operation::Trust(t) => Trust::as_bytes(t).to_vec(),
-            Operation::Unknown => vec![],
-        }
+        let mut buf = vec![0; op.size()];
+        op.write_serialized(&mut buf);
+        buf
     }
 
     fn fixed_width() -> Option<usize> {
@@ -109,6 +120,10 @@ impl redb::Value for Operation {
     where
         Self: 'a,
     {
-        Self::deserialize(data)
+        // `Unknown` is written as nothing, anything else that doesn't decode is a broken database
+        if data.is_empty() {
+            return Operation::Unknown;
+        }
+        Self::try_from(data).unwrap_or_else(|err| panic!("invalid operation in database: {}", err))
     }
 }
@@ -1,25 +1,21 @@
+use anyhow::anyhow;
 use byteorder::{ByteOrder, LE};
-use std::fmt;
+use std::{
+    fmt,
+    time::{SystemTime, UNIX_EPOCH},
+};
 
+use super::decode::{DecodeError, Reader};
+use crate::key::{PublicKey, SecretKey};
 use crate::OperationOps;
 
-#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
 pub struct Transfer {
     pub ts: u32,
     pub hops: Vec<Hop>,
     pub sigs: Vec<PeerSig>,
 }
 
-impl Default for Transfer {
-    fn default() -> Self {
-        Transfer {
-            ts: 0,
-            hops: vec![],
-            sigs: vec![],
-        }
-    }
-}
-
 impl fmt::Display for Transfer {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "<transfer ")?;
@@ -33,57 +29,278 @@ impl fmt::Display for Transfer {
 impl OperationOps for Transfer {
     const TAG: u8 = b'x';
 
+    // the signatures come right after the hops, preceded by their count, so the part that is
+    // signed doesn't change as signatures are collected
     fn write_serialized(&self, buf: &mut [u8]) {
+        let start = self.size_nosig();
+        self.write_nosig(&mut buf[0..start]);
+        buf[start] = self
+            .sigs
+            .len()
+            .try_into()
+            .expect("can't have more than 255 signatures");
+
+        for (i, peer_sig) in self.sigs.iter().enumerate() {
+            let at = start + 1 + i * PeerSig::SIZE;
+            peer_sig.write_to(&mut buf[at..at + PeerSig::SIZE]);
+        }
+    }
+
+    fn write_nosig(&self, buf: &mut [u8]) {
         buf[0] = Transfer::TAG;
         LE::write_u32(&mut buf[1..5], self.ts);
         buf[5] = self
             .hops
             .len()
             .try_into()
-            .expect("can'self have more than 128 hops");
-        buf[6] = self
-            .sigs
-            .len()
-            .try_into()
-            .expect("can'self have more than 128 hops");
+            .expect("can't have more than 255 hops");
 
         for (i, hop) in self.hops.iter().enumerate() {
-            hop.write_to(&mut buf[7 + i * Hop::SIZE..7 + (i + 1) * Hop::SIZE]);
+            hop.write_to(&mut buf[6 + i * Hop::SIZE..6 + (i + 1) * Hop::SIZE]);
         }
     }
 
     fn size(&self) -> usize {
-        return self.size_nosig() + self.sigs.len() * PeerSig::SIZE;
+        self.size_nosig() + 1 + self.sigs.len() * PeerSig::SIZE
     }
 
     fn size_nosig(&self) -> usize {
-        return 1 + 4 + self.hops.len() * Hop::SIZE;
+        1 + 4 + 1 + self.hops.len() * Hop::SIZE
     }
+}
+
+impl TryFrom<&[u8]> for Transfer {
+    type Error = DecodeError;
 
-    fn deserialize(buf: &[u8]) -> Self {
-        let mut i = 0;
+    fn try_from(buf: &[u8]) -> Result<Self, Self::Error> {
+        let mut r = Reader::new(buf);
+        r.tag(Transfer::TAG)?;
+        let ts = r.u32()?;
 
-        let nhops = buf[5].into();
+        let nhops = r.u8()? as usize;
         let mut hops = Vec::with_capacity(nhops);
-        while i < nhops {
-            hops.push(Hop::from_bytes(&buf[7 + i..]));
-            i += Hop::SIZE;
+        for _ in 0..nhops {
+            hops.push(Hop::read_from(&mut r)?);
         }
 
-        i = 0;
-        let start: usize = 7 + nhops * Hop::SIZE;
-        let nsigs = buf[6].into();
+        // every hop has at most two peers and each of them signs only once
+        let offset = r.offset;
+        let nsigs = r.u8()? as usize;
+        if nsigs > nhops * 2 {
+            return Err(DecodeError::TooManySignatures { offset, nsigs });
+        }
         let mut sigs = Vec::with_capacity(nsigs);
-        while i < nhops {
-            sigs.push(PeerSig::from_bytes(&buf[start + i..]));
-            i += PeerSig::SIZE;
+        for _ in 0..nsigs {
+            sigs.push(PeerSig::read_from(&mut r)?);
+        }
+
+        r.finish()?;
+        Ok(Transfer { ts, hops, sigs })
+    }
+}
+
+impl Transfer {
+    pub fn builder() -> TransferBuilder {
+        TransferBuilder::new_with_time(SystemTime::now())
+    }
+
+    // peers that end up with less than they had after all the hops are applied, these are the
+    // ones that must sign. peers that just pass value along (receive and send the same amount)
+    // don't have to.
+    pub fn senders(&self) -> Vec<u32> {
+        let mut deltas: Vec<(u32, i64)> = Vec::with_capacity(self.hops.len() * 2);
+        let mut add = |peer_idx: u32, delta: i64| {
+            match deltas.iter_mut().find(|(idx, _)| *idx == peer_idx) {
+                Some((_, total)) => *total += delta,
+                None => deltas.push((peer_idx, delta)),
+            };
+        };
+
+        for hop in self.hops.iter() {
+            add(hop.from, -(hop.amount as i64));
+            add(hop.to, hop.amount as i64);
+        }
+
+        deltas
+            .into_iter()
+            .filter_map(|(peer_idx, delta)| if delta < 0 { Some(peer_idx) } else { None })
+            .collect()
+    }
+
+    // merges several hop chains (e.g. the routes given by a router) into the hops of a single
+    // transfer: amounts going through the same line are added up and opposite directions cancel
+    // out, so every line shows up at most once and the whole thing is validated atomically. it fails
+    // when what goes through a line adds up to more than a hop can carry.
+    pub fn merge_paths(paths: &[Vec<Hop>]) -> Result<Vec<Hop>, anyhow::Error> {
+        // (lower peer, higher peer, amount going from lower to higher)
+        let mut merged: Vec<(u32, u32, i64)> = Vec::new();
+        for hop in paths.iter().flatten() {
+            let (low, high, amount) = if hop.from < hop.to {
+                (hop.from, hop.to, hop.amount as i64)
+            } else {
+                (hop.to, hop.from, -(hop.amount as i64))
+            };
+
+            match merged.iter_mut().find(|(l, h, _)| *l == low && *h == high) {
+                Some((_, _, total)) => *total += amount,
+                None => merged.push((low, high, amount)),
+            }
+        }
+
+        merged
+            .into_iter()
+            .filter(|(_, _, amount)| *amount != 0)
+            .map(|(low, high, amount)| {
+                let (from, to) = if amount > 0 { (low, high) } else { (high, low) };
+                let amount = u32::try_from(amount.abs()).map_err(|_| {
+                    anyhow!(
+                        "{} going from {} to {} doesn't fit in a hop",
+                        amount.abs(),
+                        from,
+                        to
+                    )
+                })?;
+                Ok(Hop { from, to, amount })
+            })
+            .collect()
+    }
+}
+
+// builds a transfer that has to be signed by more than one party: add all the hops first, then
+// pass `sighash()` around, collect each sender's signature with `add_signature()` and check them
+// against the keys the senders really have with `finalize()`
+#[derive(Debug, Clone)]
+pub struct TransferBuilder {
+    transfer: Transfer,
+}
+
+impl TransferBuilder {
+    pub fn new_with_time(when: SystemTime) -> Self {
+        TransferBuilder {
+            transfer: Transfer {
+                ts: when
+                    .duration_since(UNIX_EPOCH)
+                    .expect("time went backwards")
+                    .as_secs() as u32,
+                hops: vec![],
+                sigs: vec![],
+            },
+        }
+    }
+
+    // hops can't be added once someone has signed, as that would change what they signed
+    pub fn add_hop(&mut self, hop: Hop) -> Result<&mut Self, anyhow::Error> {
+        if !self.transfer.sigs.is_empty() {
+            return Err(anyhow!("can't add hops after signatures were added"));
         }
+        if self.transfer.hops.len() == u8::MAX as usize {
+            return Err(anyhow!("can't have more than 255 hops"));
+        }
+        if hop.amount == 0 {
+            return Err(anyhow!("hop can't have zero amount"));
+        }
+
+        self.transfer.hops.push(hop);
+        Ok(self)
+    }
 
-        Transfer {
-            ts: LE::read_u32(&buf[1..5]),
-            hops,
-            sigs,
+    pub fn add_hops(
+        &mut self,
+        hops: impl IntoIterator<Item = Hop>,
+    ) -> Result<&mut Self, anyhow::Error> {
+        for hop in hops {
+            self.add_hop(hop)?;
         }
+        Ok(self)
+    }
+
+    pub fn senders(&self) -> Vec<u32> {
+        self.transfer.senders()
+    }
+
+    pub fn sighash(&self) -> [u8; 32] {
+        self.transfer.sighash()
+    }
+
+    // senders whose signatures we still don't have
+    pub fn missing_signatures(&self) -> Vec<u32> {
+        self.senders()
+            .into_iter()
+            .filter(|sender| {
+                !self
+                    .transfer
+                    .sigs
+                    .iter()
+                    .any(|peer_sig| peer_sig.peer_idx == *sender)
+            })
+            .collect()
+    }
+
+    // checks the signature against the key of the peer that made it before accepting it.
+    // a second signature from the same peer replaces the first.
+    pub fn add_signature(
+        &mut self,
+        key: &PublicKey,
+        peer_sig: PeerSig,
+    ) -> Result<&mut Self, anyhow::Error> {
+        if !self.senders().contains(&peer_sig.peer_idx) {
+            return Err(anyhow!(
+                "peer {} doesn't have to sign this transfer",
+                peer_sig.peer_idx
+            ));
+        }
+
+        key.verify(&peer_sig.sig, &self.sighash())
+            .map_err(|err| anyhow!("invalid signature from {}: {}", peer_sig.peer_idx, err))?;
+
+        self.transfer
+            .sigs
+            .retain(|existing| existing.peer_idx != peer_sig.peer_idx);
+        self.transfer.sigs.push(peer_sig);
+        Ok(self)
+    }
+
+    // signs as one of the senders
+    pub fn sign(
+        &mut self,
+        secret_key: &SecretKey,
+        peer_idx: u32,
+    ) -> Result<&mut Self, anyhow::Error> {
+        let peer_sig = PeerSig {
+            peer_idx,
+            sig: secret_key.sign(self.sighash()),
+        };
+        self.add_signature(&secret_key.public(), peer_sig)
+    }
+
+    // signatures were only checked against the keys they came with, `key_of` gives the key each
+    // peer index really has, as in the registry state
+    pub fn finalize(
+        self,
+        key_of: impl Fn(u32) -> Option<PublicKey>,
+    ) -> Result<Transfer, anyhow::Error> {
+        if self.transfer.hops.is_empty() {
+            return Err(anyhow!("transfer has no hops"));
+        }
+
+        let missing = self.missing_signatures();
+        if !missing.is_empty() {
+            return Err(anyhow!("missing signatures from senders {:?}", missing));
+        }
+
+        let sighash = self.sighash();
+        for peer_sig in self.transfer.sigs.iter() {
+            let key = key_of(peer_sig.peer_idx)
+                .ok_or_else(|| anyhow!("no key for peer {}", peer_sig.peer_idx))?;
+            key.verify(&peer_sig.sig, &sighash).map_err(|_| {
+                anyhow!(
+                    "signature from {} wasn't made with its key",
+                    peer_sig.peer_idx
+                )
+            })?;
+        }
+
+        Ok(self.transfer)
     }
 }
 
@@ -103,11 +320,6 @@ impl redb::Value for Transfer {
     {
         let mut buf = vec![0; t.size()];
         t.write_serialized(&mut buf);
-        let start: usize = 7 + t.hops.len() * Hop::SIZE;
-        for (i, hsig) in t.sigs.iter().enumerate() {
-            hsig.write_to(&mut buf[start + i * PeerSig::SIZE..start + (i + 1) * PeerSig::SIZE]);
-        }
-
         buf
     }
 
@@ -119,11 +331,11 @@ impl redb::Value for Transfer {
     where
         Self: 'a,
     {
-        Self::deserialize(data)
+        Self::try_from(data).expect("invalid transfer in database")
     }
 }
 
-#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
+#[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
 pub struct Hop {
     pub from: u32,
     pub to: u32,
@@ -133,12 +345,12 @@ pub struct Hop {
 impl Hop {
     const SIZE: usize = 12;
 
-    fn from_bytes(data: &[u8]) -> Hop {
-        Hop {
-            from: LE::read_u32(&data[0..4]),
-            amount: LE::read_u32(&data[4..8]),
-            to: LE::read_u32(&data[8..12]),
-        }
+    fn read_from(r: &mut Reader) -> Result<Hop, DecodeError> {
+        Ok(Hop {
+            from: r.u32()?,
+            amount: r.u32()?,
+            to: r.u32()?,
+        })
     }
 
     fn write_to(&self, buf: &mut [u8]) {
@@ -154,7 +366,7 @@ impl std::fmt::Display for Hop {
     }
 }
 
-#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
+#[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
 pub struct PeerSig {
     pub peer_idx: u32,
     #[serde(with = "hex::serde")]
@@ -164,11 +376,11 @@ pub struct PeerSig {
 impl PeerSig {
     const SIZE: usize = 68;
 
-    fn from_bytes(data: &[u8]) -> PeerSig {
-        PeerSig {
-            peer_idx: LE::read_u32(&data[0..4]),
-            sig: data[4..68].try_into().unwrap(),
-        }
+    fn read_from(r: &mut Reader) -> Result<PeerSig, DecodeError> {
+        Ok(PeerSig {
+            peer_idx: r.u32()?,
+            sig: r.signature()?,
+        })
     }
 
     fn write_to(&self, buf: &mut [u8]) {
@@ -1,17 +1,15 @@
 use byteorder::{ByteOrder, LE};
-use secp256k1::{
-    hashes::{sha256, Hash},
-    XOnlyPublicKey,
-};
+use secp256k1::XOnlyPublicKey;
 use std::{
     fmt,
     time::{SystemTime, UNIX_EPOCH},
 };
 
+use super::decode::{DecodeError, Reader};
 use crate::key::{PublicKey, SecretKey};
 use crate::OperationOps;
 
-#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
 pub struct Trust {
     pub ts: u32,
     pub from: u32,
@@ -50,6 +48,11 @@ impl OperationOps for Trust {
     const TAG: u8 = b't';
 
     fn write_serialized(&self, buf: &mut [u8]) {
+        self.write_nosig(&mut buf[0..45]);
+        buf[45..109].copy_from_slice(&self.sig);
+    }
+
+    fn write_nosig(&self, buf: &mut [u8]) {
         buf[0] = Trust::TAG;
         LE::write_u32(&mut buf[1..5], self.ts);
         LE::write_u32(&mut buf[5..9], self.from);
@@ -64,15 +67,23 @@ impl OperationOps for Trust {
     fn size(&self) -> usize {
         Trust::SIZE
     }
+}
 
-    fn deserialize(buf: &[u8]) -> Self {
-        Trust {
-            ts: LE::read_u32(&buf[1..5]),
-            from: LE::read_u32(&buf[5..9]),
-            to: PublicKey(XOnlyPublicKey::from_slice(&buf[9..41]).unwrap()),
-            amount: LE::read_u32(&buf[41..45]),
-            sig: buf[45..109].try_into().unwrap(),
-        }
+impl TryFrom<&[u8]> for Trust {
+    type Error = DecodeError;
+
+    fn try_from(buf: &[u8]) -> Result<Self, Self::Error> {
+        let mut r = Reader::new(buf);
+        r.tag(Trust::TAG)?;
+        let trust = Trust {
+            ts: r.u32()?,
+            from: r.u32()?,
+            to: r.public_key()?,
+            amount: r.u32()?,
+            sig: r.signature()?,
+        };
+        r.finish()?;
+        Ok(trust)
     }
 }
 
@@ -103,11 +114,7 @@ impl Trust {
         };
 
         // sign
-        let mut nosig = vec![0; t.size_nosig()];
-        t.write_serialized(&mut nosig);
-        let digest = sha256::Hash::hash(&nosig);
-        let message = secp256k1::Message::from_digest(digest.to_byte_array());
-        t.sig = secret_key.0.sign_schnorr(message).serialize();
+        t.sig = secret_key.sign(t.sighash());
 
         t
     }
@@ -129,7 +136,6 @@ impl redb::Value for Trust {
     {
         let mut buf = vec![0; Trust::SIZE];
         t.write_serialized(&mut buf);
-        buf[45..109].copy_from_slice(&t.sig);
         buf
     }
 
@@ -141,6 +147,6 @@ impl redb::Value for Trust {
     where
         Self: 'a,
     {
-        Self::deserialize(data)
+        Self::try_from(data).expect("invalid trust in database")
     }
 }
@@ -0,0 +1,97 @@
+use std::fmt;
+
+// everything that can make `validate` reject an operation
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+#[serde(tag = "error", rename_all = "snake_case")]
+pub enum ValidationError {
+    // operations we can't parse must never be stored
+    UnknownOperation,
+    // a key index that isn't in the state
+    UnknownKey {
+        idx: u32,
+    },
+    // a trust from a key to itself
+    SelfTrust {
+        idx: u32,
+    },
+    // a transfer without hops moves nothing and has nobody to sign it
+    EmptyTransfer,
+    // a transfer can't be encoded with more than 255 hops
+    TooManyHops {
+        count: usize,
+    },
+    // more signatures than can be encoded, or than there are peers in the hops
+    TooManySignatures {
+        count: usize,
+    },
+    // this key signed the same transfer more than once
+    DuplicateSigner {
+        idx: u32,
+    },
+    // hop at this position in the transfer moves nothing
+    ZeroAmountHop {
+        hop: usize,
+    },
+    // no one has trusted anyone between the two peers of this hop yet
+    NoLine {
+        hop: usize,
+        line: u64,
+    },
+    // the hop at this position wants to send more than what is available in its line
+    InsufficientCredit {
+        hop: usize,
+        line: u64,
+        available: i64,
+    },
+    // this key has to sign the operation but didn't
+    MissingSigner {
+        idx: u32,
+    },
+    // the signature attributed to this key doesn't verify
+    BadSignature {
+        idx: u32,
+    },
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ValidationError::UnknownOperation => write!(f, "unknown operation"),
+            ValidationError::UnknownKey { idx } => write!(f, "key {} doesn't exist", idx),
+            ValidationError::SelfTrust { idx } => write!(f, "key {} can't trust itself", idx),
+            ValidationError::EmptyTransfer => write!(f, "transfer has no hops"),
+            ValidationError::TooManyHops { count } => {
+                write!(f, "{} hops is more than the 255 a transfer can have", count)
+            }
+            ValidationError::TooManySignatures { count } => {
+                write!(f, "{} signatures is more than the transfer can have", count)
+            }
+            ValidationError::DuplicateSigner { idx } => {
+                write!(f, "key {} signed more than once", idx)
+            }
+            ValidationError::ZeroAmountHop { hop } => write!(f, "hop {} has zero amount", hop),
+            ValidationError::NoLine { hop, line } => {
+                write!(
+                    f,
+                    "hop {} goes through line {:x}, which doesn't exist",
+                    hop, line
+                )
+            }
+            ValidationError::InsufficientCredit {
+                hop,
+                line,
+                available,
+            } => write!(
+                f,
+                "hop {} needs more than the {} available in line {:x}",
+                hop, available, line
+            ),
+            ValidationError::MissingSigner { idx } => {
+                write!(f, "missing signature from sender {}", idx)
+            }
+            ValidationError::BadSignature { idx } => write!(f, "invalid signature from {}", idx),
+        }
+    }
+}
+
+impl std::error::Error for ValidationError {}
@@ -1,6 +1,6 @@
 use byteorder::{ByteOrder, LE};
 
-#[derive(Debug, Clone, serde::Serialize)]
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
 pub struct Line {
     // peers sorted by serial number
     pub peers: (u32, u32),
@@ -11,7 +11,7 @@ pub struct Line {
 }
 
 impl Line {
-    const SIZE: usize = 4 + 4 + 4 + 4 + 8;
+    pub(crate) const SIZE: usize = 4 + 4 + 4 + 4 + 8;
 
     pub fn build_key(peer1: u32, peer2: u32) -> u64 {
         let (first, second) = if peer1 < peer2 {
@@ -22,6 +22,32 @@ impl Line {
 
         ((first as u64) << 32) | second as u64
     }
+
+    // how much `from` can still send to the other peer before exceeding the trust it was given
+    pub fn can_send(&self, from: u32) -> i64 {
+        if from == self.peers.0 {
+            self.trust.0 as i64 - self.balance
+        } else {
+            self.trust.1 as i64 + self.balance
+        }
+    }
+
+    // [peers.0][peers.1][trust.0][trust.1][balance]
+    pub(crate) fn write(&self, buf: &mut [u8]) {
+        LE::write_u32(&mut buf[0..4], self.peers.0);
+        LE::write_u32(&mut buf[4..8], self.peers.1);
+        LE::write_u32(&mut buf[8..12], self.trust.0);
+        LE::write_u32(&mut buf[12..16], self.trust.1);
+        LE::write_i64(&mut buf[16..24], self.balance);
+    }
+
+    pub(crate) fn read(buf: &[u8]) -> Self {
+        Line {
+            peers: (LE::read_u32(&buf[0..4]), LE::read_u32(&buf[4..8])),
+            trust: (LE::read_u32(&buf[8..12]), LE::read_u32(&buf[12..16])),
+            balance: LE::read_i64(&buf[16..24]),
+        }
+    }
 }
 
 #[cfg(feature = "redb")]
@@ -39,11 +65,7 @@ impl redb::Value for Line {
         Self: 'b,
     {
         let mut buf = vec![0; Line::SIZE];
-        LE::write_u32(&mut buf[0..4], line.peers.0);
-        LE::write_u32(&mut buf[4..8], line.peers.1);
-        LE::write_u32(&mut buf[8..12], line.trust.0);
-        LE::write_u32(&mut buf[12..16], line.trust.1);
-        LE::write_i64(&mut buf[16..24], line.balance);
+        line.write(&mut buf);
         buf
     }
 
@@ -55,10 +77,6 @@ impl redb::Value for Line {
     where
         Self: 'a,
     {
-        Line {
-            peers: (LE::read_u32(&data[0..4]), LE::read_u32(&data[4..8])),
-            trust: (LE::read_u32(&data[8..12]), LE::read_u32(&data[12..16])),
-            balance: LE::read_i64(&data[16..24]),
-        }
+        Line::read(data)
     }
 }
@@ -1,17 +1,22 @@
-use anyhow::anyhow;
 use std::{
     collections::{hash_map::Entry, HashMap},
     hash::BuildHasherDefault,
 };
 
+mod error;
 pub mod line;
+pub mod root;
+mod snapshot;
 
+pub use error::ValidationError;
 pub use line::Line;
+pub use root::StateTree;
+pub use snapshot::SnapshotError;
 
 use crate::operation::{Operation, OperationOps};
 use crate::PublicKey;
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct State {
     pub keys: Vec<PublicKey>,
     pub key_indexes: HashMap<[u8; 32], u32>,
@@ -19,110 +24,115 @@ pub struct State {
 }
 
 // just check if everything is ok to be applied
-pub fn validate(state: &State, op: &Operation) -> Result<(), anyhow::Error> {
+pub fn validate(state: &State, op: &Operation) -> Result<(), ValidationError> {
     match op {
-        Operation::Unknown => return Err(anyhow!("Unknown shouldn't have been stored")),
+        Operation::Unknown => Err(ValidationError::UnknownOperation),
         Operation::Trust(t) => {
             // get idx of _to_ key or add new key to list
             let key: [u8; 32] = t.to.serialize();
-            match state.key_indexes.get(&key) {
-                Some(idx) => {
-                    // can't trust yourself
-                    if *idx == t.from {
-                        return Err(anyhow!("can't trust yourself"));
-                    }
+            if let Some(idx) = state.key_indexes.get(&key) {
+                // can't trust yourself
+                if *idx == t.from {
+                    return Err(ValidationError::SelfTrust { idx: t.from });
                 }
-                None => {}
             }
 
-            // check existence of t.from
-            let _ = match state.keys.get(t.from as usize) {
-                None => return Err(anyhow!("from key doesn't exist")),
-                Some(key) => key.verify(&t.sig, &t.sighash()),
-            };
-
-            Ok(())
+            // check existence of t.from and its signature
+            match state.keys.get(t.from as usize) {
+                None => Err(ValidationError::UnknownKey { idx: t.from }),
+                Some(key) => key
+                    .verify(&t.sig, &t.sighash())
+                    .map_err(|_| ValidationError::BadSignature { idx: t.from }),
+            }
         }
         Operation::Transfer(t) => {
-            // we'll use this to check who are the senders, i.e. who lost money
-            let mut deltas: Vec<Delta> = Vec::with_capacity(t.hops.len() * 2);
-            struct Delta {
-                peer_idx: u32,
-                delta: i64,
+            // it has to fit in what the log can encode and decode again, before anything hashes it
+            if t.hops.is_empty() {
+                return Err(ValidationError::EmptyTransfer);
+            }
+            if t.hops.len() > u8::MAX as usize {
+                return Err(ValidationError::TooManyHops {
+                    count: t.hops.len(),
+                });
+            }
+            if t.sigs.len() > u8::MAX as usize || t.sigs.len() > t.hops.len() * 2 {
+                return Err(ValidationError::TooManySignatures {
+                    count: t.sigs.len(),
+                });
+            }
+            for (i, isig) in t.sigs.iter().enumerate() {
+                if t.sigs[..i].iter().any(|s| s.peer_idx == isig.peer_idx) {
+                    return Err(ValidationError::DuplicateSigner { idx: isig.peer_idx });
+                }
             }
 
-            // meanwhile we'll also check if each transfer is allowed according by the existing trust
-            for hop in t.hops.iter() {
+            // balance changes caused by the hops we've already checked, so a transfer that goes
+            // through the same line more than once is checked against what is left after each
+            let mut pending: Vec<(u64, i64)> = Vec::with_capacity(t.hops.len());
+
+            // check if each transfer is allowed according by the existing trust
+            for (i, hop) in t.hops.iter().enumerate() {
                 // check if hop has any amount whatsoever
                 if hop.amount == 0 {
-                    return Err(anyhow!("hop can't have zero amount"));
+                    return Err(ValidationError::ZeroAmountHop { hop: i });
+                }
+
+                // check if both peers exist
+                for idx in [hop.from, hop.to] {
+                    if idx as usize >= state.keys.len() {
+                        return Err(ValidationError::UnknownKey { idx });
+                    }
                 }
 
                 // check if there is enough trust
-                match state.lines.get(&Line::build_key(hop.from, hop.to)) {
-                    None => return Err(anyhow!("no line available for transfer")),
+                let line_key = Line::build_key(hop.from, hop.to);
+                match state.lines.get(&line_key) {
+                    None => {
+                        return Err(ValidationError::NoLine {
+                            hop: i,
+                            line: line_key,
+                        })
+                    }
                     Some(line) => {
-                        let can_send = if hop.from == line.peers.0 {
-                            line.trust.0 as i64 - line.balance
-                        } else {
-                            line.trust.1 as i64 + line.balance
-                        } as i64;
-                        if hop.to as i64 > can_send {
-                            return Err(anyhow!("not enough credit in line"));
+                        // this is how the balance moves when `from` sends
+                        let direction = if hop.from == line.peers.0 { 1 } else { -1 };
+                        let delta = match pending.iter_mut().find(|(key, _)| *key == line_key) {
+                            Some((_, delta)) => delta,
+                            None => {
+                                pending.push((line_key, 0));
+                                &mut pending.last_mut().unwrap().1
+                            }
+                        };
+
+                        let available = line.can_send(hop.from) - direction * *delta;
+                        if hop.amount as i64 > available {
+                            return Err(ValidationError::InsufficientCredit {
+                                hop: i,
+                                line: line_key,
+                                available,
+                            });
                         }
+
+                        *delta += direction * hop.amount as i64;
                     }
                 }
-
-                // check who lost money in this transfer
-                let fidx = deltas
-                    .iter()
-                    .position(|delta| delta.peer_idx == hop.from)
-                    .unwrap_or_else(|| {
-                        let idx = deltas.len();
-                        deltas.push(Delta {
-                            peer_idx: hop.from,
-                            delta: 0,
-                        });
-                        idx
-                    });
-                deltas[fidx].delta -= hop.amount as i64;
-                let tidx = deltas
-                    .iter()
-                    .position(|delta| delta.peer_idx == hop.to)
-                    .unwrap_or_else(|| {
-                        let idx = deltas.len();
-                        deltas.push(Delta {
-                            peer_idx: hop.to,
-                            delta: 0,
-                        });
-                        idx
-                    });
-                deltas[tidx].delta += hop.amount as i64;
             }
 
             // people who lost money in this must have provided a signature
-            let senders = deltas.iter().filter_map(|delta| {
-                if delta.delta < 0 {
-                    Some(delta.peer_idx)
-                } else {
-                    None
-                }
-            });
-            for sender in senders {
-                if t.sigs
-                    .iter()
-                    .find(|peer_sig| peer_sig.peer_idx == sender)
-                    .is_none()
-                {
-                    return Err(anyhow!("missing signature from sender {}", sender));
+            for sender in t.senders() {
+                if !t.sigs.iter().any(|peer_sig| peer_sig.peer_idx == sender) {
+                    return Err(ValidationError::MissingSigner { idx: sender });
                 }
             }
 
             // verify all signatures
+            let sighash = t.sighash();
             for isig in t.sigs.iter() {
-                let _ = match state.keys.get(isig.peer_idx as usize) {
-                    None => return Err(anyhow!("signing key doesn't exist")),
-                    Some(key) => key.verify(&isig.sig, &t.sighash()),
+                match state.keys.get(isig.peer_idx as usize) {
+                    None => return Err(ValidationError::UnknownKey { idx: isig.peer_idx }),
+                    Some(key) => key
+                        .verify(&isig.sig, &sighash)
+                        .map_err(|_| ValidationError::BadSignature { idx: isig.peer_idx })?,
                 };
             }
 
@@ -154,9 +164,9 @@ pub fn process(state: &mut State, op: &Operation) {
                     let line = entry.get_mut();
 
                     if t.from < to_idx {
-                        line.trust.0 = t.amount;
-                    } else {
                         line.trust.1 = t.amount;
+                    } else {
+                        line.trust.0 = t.amount;
                     }
                 }
                 Entry::Vacant(entry) => {
@@ -183,12 +193,11 @@ pub fn process(state: &mut State, op: &Operation) {
                     .get_mut(&Line::build_key(hop.from, hop.to))
                     .expect("we have just checked this");
 
-                line.balance = line.balance
-                    + if line.peers.0 == hop.from {
-                        hop.amount as i64
-                    } else {
-                        -(hop.amount as i64)
-                    }
+                line.balance += if line.peers.0 == hop.from {
+                    hop.amount as i64
+                } else {
+                    -(hop.amount as i64)
+                }
             }
         }
     }
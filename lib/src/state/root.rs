@@ -0,0 +1,235 @@
+use secp256k1::hashes::{sha256, Hash, HashEngine};
+use std::collections::{BTreeMap, HashMap};
+
+use super::{Line, State};
+use crate::merkle;
+use crate::operation::Operation;
+
+// a single hash that commits to everything in a state, so the registry can sign it together with
+// the log and anyone can check a line against it without having the whole state.
+//
+// it is the hash of two roots: the RFC 6962 tree over the keys in index order (the same tree we
+// use for the log, with each key taking the place of an entry hash) and a sparse merkle tree over
+// the lines keyed by `Line::build_key`.
+//
+// the sparse tree has 64 levels, one per bit of the key from the most significant, but subtrees
+// are collapsed: an empty subtree hashes to `EMPTY`, one with a single line to that line's leaf
+// hash, and only those with two or more lines to the `merkle::node_hash` of their two halves.
+pub const EMPTY: [u8; 32] = [0; 32];
+
+// [0x00][peers.0][peers.1][trust.0][trust.1][balance]
+pub fn leaf_hash(line: &Line) -> [u8; 32] {
+    let mut buf = [0u8; 1 + Line::SIZE];
+    line.write(&mut buf[1..]);
+    sha256::Hash::hash(&buf).to_byte_array()
+}
+
+pub fn state_root(keys_root: &[u8; 32], lines_root: &[u8; 32]) -> [u8; 32] {
+    let mut engine = sha256::Hash::engine();
+    engine.input(keys_root);
+    engine.input(lines_root);
+    sha256::Hash::from_engine(engine).to_byte_array()
+}
+
+// the first `depth` bits of `key`
+fn prefix(key: u64, depth: u8) -> u64 {
+    key.checked_shr(64 - depth as u32).unwrap_or(0)
+}
+
+// the keys that start with `prefix`
+fn range(depth: u8, prefix: u64) -> std::ops::RangeInclusive<u64> {
+    let start = prefix.checked_shl(64 - depth as u32).unwrap_or(0);
+    start..=start | u64::MAX.checked_shr(depth as u32).unwrap_or(0)
+}
+
+// the sparse merkle tree over the lines, keeping every hash we need to update a line or build a
+// proof without going through all of them
+#[derive(Debug, Clone, Default)]
+pub struct LineTree {
+    leaves: BTreeMap<u64, [u8; 32]>,
+    // hashes of the subtrees with at least two lines in them, by depth and prefix
+    nodes: HashMap<(u8, u64), [u8; 32]>,
+}
+
+impl LineTree {
+    pub fn new<'a>(lines: impl IntoIterator<Item = &'a Line>) -> Self {
+        let mut tree = LineTree {
+            leaves: lines
+                .into_iter()
+                .map(|line| (Line::build_key(line.peers.0, line.peers.1), leaf_hash(line)))
+                .collect(),
+            nodes: HashMap::new(),
+        };
+
+        let leaves: Vec<(u64, [u8; 32])> = tree.leaves.iter().map(|(k, h)| (*k, *h)).collect();
+        tree.build(0, 0, &leaves);
+        tree
+    }
+
+    fn build(&mut self, depth: u8, prefix: u64, leaves: &[(u64, [u8; 32])]) -> [u8; 32] {
+        match leaves {
+            [] => EMPTY,
+            [(_, hash)] => *hash,
+            _ => {
+                // leaves are sorted so those with a 0 in this bit come first
+                let split = leaves.partition_point(|(key, _)| (key >> (63 - depth)) & 1 == 0);
+                let left = self.build(depth + 1, prefix << 1, &leaves[..split]);
+                let right = self.build(depth + 1, (prefix << 1) | 1, &leaves[split..]);
+                let hash = merkle::node_hash(&left, &right);
+                self.nodes.insert((depth, prefix), hash);
+                hash
+            }
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.leaves.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.leaves.is_empty()
+    }
+
+    pub fn root(&self) -> [u8; 32] {
+        self.node(0, 0)
+    }
+
+    // adds the line or replaces what we had for it, rehashing only the subtrees it is in
+    pub fn update(&mut self, line: &Line) {
+        let key = Line::build_key(line.peers.0, line.peers.1);
+        self.leaves.insert(key, leaf_hash(line));
+
+        for depth in (0..64).rev() {
+            let prefix = prefix(key, depth);
+            if self.leaves.range(range(depth, prefix)).nth(1).is_none() {
+                continue;
+            }
+            let hash = merkle::node_hash(
+                &self.node(depth + 1, prefix << 1),
+                &self.node(depth + 1, (prefix << 1) | 1),
+            );
+            self.nodes.insert((depth, prefix), hash);
+        }
+    }
+
+    // the siblings of the subtrees the line between these peers is in, from the root down to where
+    // it is alone
+    pub fn proof(&self, peer1: u32, peer2: u32) -> Option<Vec<[u8; 32]>> {
+        let key = Line::build_key(peer1, peer2);
+        if !self.leaves.contains_key(&key) {
+            return None;
+        }
+
+        let mut path = Vec::new();
+        for depth in 0..64 {
+            if self
+                .leaves
+                .range(range(depth, prefix(key, depth)))
+                .nth(1)
+                .is_none()
+            {
+                break;
+            }
+            path.push(self.node(depth + 1, prefix(key, depth + 1) ^ 1));
+        }
+        Some(path)
+    }
+
+    fn node(&self, depth: u8, prefix: u64) -> [u8; 32] {
+        let mut leaves = self.leaves.range(range(depth, prefix));
+        match (leaves.next(), leaves.next()) {
+            (None, _) => EMPTY,
+            (Some((_, hash)), None) => *hash,
+            _ => self.nodes[&(depth, prefix)],
+        }
+    }
+}
+
+// the lines root we get going up from `line` through `path`, which is what `LineTree::proof` gives.
+// none if that can't be a line in the tree.
+pub fn root_from_proof(line: &Line, path: &[[u8; 32]]) -> Option<[u8; 32]> {
+    if line.peers.0 >= line.peers.1 || path.len() > 64 {
+        return None;
+    }
+
+    let key = Line::build_key(line.peers.0, line.peers.1);
+    let mut hash = leaf_hash(line);
+    for (depth, sibling) in path.iter().enumerate().rev() {
+        hash = match (key >> (63 - depth)) & 1 {
+            0 => merkle::node_hash(&hash, sibling),
+            _ => merkle::node_hash(sibling, &hash),
+        };
+    }
+    Some(hash)
+}
+
+pub fn verify_line(line: &Line, path: &[[u8; 32]], lines_root: &[u8; 32]) -> bool {
+    root_from_proof(line, path) == Some(*lines_root)
+}
+
+// both trees behind the state root, kept next to a state and updated after each operation is
+// processed so the root doesn't have to be computed from scratch every time
+#[derive(Debug, Clone, Default)]
+pub struct StateTree {
+    keys: merkle::Tree,
+    lines: LineTree,
+}
+
+impl StateTree {
+    pub fn new(state: &State) -> Self {
+        let mut keys = merkle::Tree::new();
+        for key in state.keys.iter() {
+            keys.push(&key.serialize());
+        }
+
+        StateTree {
+            keys,
+            lines: LineTree::new(state.lines.values()),
+        }
+    }
+
+    // catches up with `state` after `op` was processed on it
+    pub fn update(&mut self, state: &State, op: &Operation) {
+        for key in state.keys[self.keys.len() as usize..].iter() {
+            self.keys.push(&key.serialize());
+        }
+
+        match op {
+            Operation::Unknown => {}
+            Operation::Trust(t) => {
+                let to = state.key_indexes[&t.to.serialize()];
+                self.lines
+                    .update(&state.lines[&Line::build_key(t.from, to)]);
+            }
+            Operation::Transfer(t) => {
+                for hop in t.hops.iter() {
+                    self.lines
+                        .update(&state.lines[&Line::build_key(hop.from, hop.to)]);
+                }
+            }
+        }
+    }
+
+    pub fn keys_root(&self) -> [u8; 32] {
+        self.keys.root()
+    }
+
+    pub fn lines_root(&self) -> [u8; 32] {
+        self.lines.root()
+    }
+
+    pub fn root(&self) -> [u8; 32] {
+        state_root(&self.keys_root(), &self.lines_root())
+    }
+
+    pub fn line_proof(&self, peer1: u32, peer2: u32) -> Option<Vec<[u8; 32]>> {
+        self.lines.proof(peer1, peer2)
+    }
+}
+
+impl State {
+    // computed from scratch, use a `StateTree` to keep it up to date instead
+    pub fn root(&self) -> [u8; 32] {
+        StateTree::new(self).root()
+    }
+}
@@ -0,0 +1,222 @@
+use byteorder::{ByteOrder, LE};
+use secp256k1::hashes::{sha256, Hash, HashEngine};
+use std::{
+    collections::HashMap,
+    fmt,
+    hash::BuildHasherDefault,
+    io::{self, Read, Write},
+};
+
+use super::{Line, State};
+use crate::PublicKey;
+
+// a whole state as bytes, so it can be saved and passed around between the registry, the router
+// and anything else. everything is little-endian:
+//
+//   [magic "cass"][version]
+//   [nkeys u32][keys, 32 bytes each, in index order]
+//   [nlines u32][lines, 24 bytes each: peers.0 u32, peers.1 u32, trust.0 u32, trust.1 u32,
+//    balance i64, sorted by peers]
+//   [sha256 of everything before]
+//
+// `key_indexes` isn't written since it follows from `keys`. lines are sorted so the same state
+// always gives the same bytes and two snapshots can be compared directly.
+const MAGIC: [u8; 4] = *b"cass";
+const VERSION: u8 = 1;
+
+// why a snapshot couldn't be read
+#[derive(Debug)]
+pub enum SnapshotError {
+    // includes the snapshot ending too early
+    Io(io::Error),
+    // not a snapshot at all
+    BadMagic,
+    // written by a version of this that we don't know
+    UnsupportedVersion { version: u8 },
+    // the bytes were changed after being written
+    ChecksumMismatch,
+    // the key at this index isn't a valid x-only public key
+    InvalidPublicKey { idx: u32 },
+    // the key at this index was already seen at a lower one
+    DuplicateKey { idx: u32 },
+    // the line at this position refers to keys we don't have, or is out of order
+    InvalidLine { position: u32 },
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SnapshotError::Io(err) => write!(f, "failed to read snapshot: {}", err),
+            SnapshotError::BadMagic => write!(f, "not a state snapshot"),
+            SnapshotError::UnsupportedVersion { version } => {
+                write!(f, "unsupported snapshot version {}", version)
+            }
+            SnapshotError::ChecksumMismatch => write!(f, "snapshot checksum doesn't match"),
+            SnapshotError::InvalidPublicKey { idx } => write!(f, "invalid public key at {}", idx),
+            SnapshotError::DuplicateKey { idx } => write!(f, "key at {} appears twice", idx),
+            SnapshotError::InvalidLine { position } => {
+                write!(f, "invalid line at position {}", position)
+            }
+        }
+    }
+}
+
+impl std::error::Error for SnapshotError {}
+
+impl From<io::Error> for SnapshotError {
+    fn from(err: io::Error) -> Self {
+        SnapshotError::Io(err)
+    }
+}
+
+// hashes everything that goes through it so the checksum doesn't need the whole snapshot in memory
+struct Hashing<T> {
+    inner: T,
+    engine: sha256::HashEngine,
+}
+
+impl<T> Hashing<T> {
+    fn new(inner: T) -> Self {
+        Hashing {
+            inner,
+            engine: sha256::Hash::engine(),
+        }
+    }
+
+    fn checksum(&self) -> [u8; 32] {
+        sha256::Hash::from_engine(self.engine.clone()).to_byte_array()
+    }
+}
+
+impl<W: Write> Hashing<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.engine.input(buf);
+        self.inner.write_all(buf)
+    }
+}
+
+impl<R: Read> Hashing<R> {
+    fn read<const N: usize>(&mut self) -> io::Result<[u8; N]> {
+        let mut buf = [0u8; N];
+        self.inner.read_exact(&mut buf)?;
+        self.engine.input(&buf);
+        Ok(buf)
+    }
+}
+
+impl State {
+    pub fn write_to(&self, writer: impl Write) -> io::Result<()> {
+        let mut w = Hashing::new(writer);
+        w.write(&MAGIC)?;
+        w.write(&[VERSION])?;
+
+        w.write(&(self.keys.len() as u32).to_le_bytes())?;
+        for key in self.keys.iter() {
+            w.write(&key.serialize())?;
+        }
+
+        let mut lines: Vec<&Line> = self.lines.values().collect();
+        lines.sort_by_key(|line| line.peers);
+        w.write(&(lines.len() as u32).to_le_bytes())?;
+        let mut buf = [0u8; Line::SIZE];
+        for line in lines {
+            line.write(&mut buf);
+            w.write(&buf)?;
+        }
+
+        let checksum = w.checksum();
+        w.inner.write_all(&checksum)
+    }
+
+    // reads exactly one snapshot, anything after it is left in `reader`
+    pub fn read_from(reader: impl Read) -> Result<State, SnapshotError> {
+        let mut r = Hashing::new(reader);
+        if r.read::<4>()? != MAGIC {
+            return Err(SnapshotError::BadMagic);
+        }
+        let [version] = r.read::<1>()?;
+        if version != VERSION {
+            return Err(SnapshotError::UnsupportedVersion { version });
+        }
+
+        // capacities are capped so a broken count can't make us allocate everything
+        let nkeys = LE::read_u32(&r.read::<4>()?);
+        let mut keys = Vec::with_capacity(nkeys.min(1 << 16) as usize);
+        let mut key_indexes = HashMap::with_capacity(nkeys.min(1 << 16) as usize);
+        for idx in 0..nkeys {
+            let bytes = r.read::<32>()?;
+            let key = PublicKey::from_slice(&bytes)
+                .map_err(|_| SnapshotError::InvalidPublicKey { idx })?;
+            if key_indexes.insert(bytes, idx).is_some() {
+                return Err(SnapshotError::DuplicateKey { idx });
+            }
+            keys.push(key);
+        }
+
+        let nlines = LE::read_u32(&r.read::<4>()?);
+        let mut lines = HashMap::with_capacity_and_hasher(
+            nlines.min(1 << 16) as usize,
+            BuildHasherDefault::default(),
+        );
+        let mut previous = None;
+        for position in 0..nlines {
+            let line = Line::read(&r.read::<{ Line::SIZE }>()?);
+            if line.peers.0 >= line.peers.1
+                || line.peers.1 >= nkeys
+                || previous.is_some_and(|peers| peers >= line.peers)
+            {
+                return Err(SnapshotError::InvalidLine { position });
+            }
+            previous = Some(line.peers);
+            lines.insert(Line::build_key(line.peers.0, line.peers.1), line);
+        }
+
+        let expected = r.checksum();
+        let mut checksum = [0u8; 32];
+        r.inner.read_exact(&mut checksum)?;
+        if checksum != expected {
+            return Err(SnapshotError::ChecksumMismatch);
+        }
+
+        Ok(State {
+            keys,
+            key_indexes,
+            lines,
+        })
+    }
+}
+
+// in human readable formats like json it is the snapshot as hex, in the others just its bytes
+impl serde::Serialize for State {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        let mut buf = Vec::new();
+        self.write_to(&mut buf).map_err(serde::ser::Error::custom)?;
+        match serializer.is_human_readable() {
+            true => serializer.serialize_str(&hex::encode(buf)),
+            false => serializer.serialize_bytes(&buf),
+        }
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for State {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let buf: Vec<u8> = match deserializer.is_human_readable() {
+            true => hex::serde::deserialize(deserializer)?,
+            false => serde::Deserialize::deserialize(deserializer)?,
+        };
+        let mut slice = buf.as_slice();
+        let state = State::read_from(&mut slice).map_err(serde::de::Error::custom)?;
+        if !slice.is_empty() {
+            return Err(serde::de::Error::custom(
+                "trailing bytes after the snapshot",
+            ));
+        }
+        Ok(state)
+    }
+}
@@ -0,0 +1,70 @@
+// fixtures shared by the tests in this workspace, key `i` is always the one of `secret(i)`
+use crate::{
+    state::{process, validate},
+    Hop, Operation, PublicKey, SecretKey, State, Transfer, Trust,
+};
+use std::collections::HashMap;
+
+pub fn secret(i: u32) -> SecretKey {
+    SecretKey::from_hex(&format!("{:064x}", i + 1)).unwrap()
+}
+
+// the key of `secret(i)`, which is always at index `i`
+pub fn public(i: u32) -> Option<PublicKey> {
+    Some(secret(i).public())
+}
+
+pub fn trust(from: u32, to: u32, amount: u32) -> Operation {
+    Operation::Trust(Trust::new(secret(from), from, secret(to).public(), amount))
+}
+
+pub fn hop(from: u32, to: u32, amount: u32) -> Hop {
+    Hop { from, to, amount }
+}
+
+// signed by every peer that has to sign it
+pub fn signed_transfer(hops: Vec<Hop>) -> Operation {
+    let mut builder = Transfer::builder();
+    builder.add_hops(hops).unwrap();
+    for idx in builder.senders() {
+        builder.sign(&secret(idx), idx).unwrap();
+    }
+    Operation::Transfer(builder.finalize(public).unwrap())
+}
+
+// signed only by `signers`, whether or not they are the ones that have to sign it
+pub fn transfer_signed_by(hops: Vec<Hop>, signers: &[u32]) -> Transfer {
+    let mut builder = Transfer::builder();
+    builder.add_hops(hops).unwrap();
+    for idx in signers {
+        builder.sign(&secret(*idx), *idx).unwrap();
+    }
+    builder.finalize(public).unwrap()
+}
+
+// `nkeys` keys, each at the index of its secret, and no lines
+pub fn keys(nkeys: u32) -> State {
+    let mut state = State {
+        keys: vec![],
+        key_indexes: HashMap::new(),
+        lines: HashMap::default(),
+    };
+    for idx in 0..nkeys {
+        state.keys.push(secret(idx).public());
+        state
+            .key_indexes
+            .insert(secret(idx).public().serialize(), idx);
+    }
+    state
+}
+
+// a state with just the registry key at index 0
+pub fn empty_state() -> State {
+    keys(1)
+}
+
+// validates and processes, panicking if the operation is invalid
+pub fn apply(state: &mut State, op: Operation) {
+    validate(state, &op).expect("operation should be valid");
+    process(state, &op);
+}
@@ -0,0 +1,85 @@
+use cassis::test_utils::{hop, public, secret};
+use cassis::{PeerSig, Transfer};
+
+#[test]
+fn signature_that_doesnt_verify_is_not_added() {
+    let mut builder = Transfer::builder();
+    builder.add_hop(hop(1, 0, 10)).unwrap();
+
+    // made by someone else, or over something else
+    let forged = PeerSig {
+        peer_idx: 1,
+        sig: secret(2).sign(builder.sighash()),
+    };
+    let stale = PeerSig {
+        peer_idx: 1,
+        sig: secret(1).sign([0; 32]),
+    };
+    for peer_sig in [forged, stale] {
+        assert!(builder
+            .add_signature(&secret(1).public(), peer_sig)
+            .is_err());
+    }
+    assert_eq!(builder.missing_signatures(), [1]);
+}
+
+#[test]
+fn signature_from_someone_who_doesnt_send_is_not_added() {
+    let mut builder = Transfer::builder();
+    builder.add_hops([hop(1, 2, 10), hop(2, 0, 10)]).unwrap();
+
+    // 2 only passes along what it gets and 0 only receives
+    for idx in [2, 0, 5] {
+        assert!(builder.sign(&secret(idx), idx).is_err(), "{}", idx);
+    }
+    builder.sign(&secret(1), 1).unwrap();
+    assert!(builder.missing_signatures().is_empty());
+    assert_eq!(builder.finalize(public).unwrap().sigs.len(), 1);
+}
+
+#[test]
+fn transfer_is_only_finalized_with_every_sender_signature() {
+    let mut builder = Transfer::builder();
+    builder.add_hops([hop(1, 0, 10), hop(2, 0, 10)]).unwrap();
+    builder.sign(&secret(1), 1).unwrap();
+    assert_eq!(builder.missing_signatures(), [2]);
+    assert!(builder.clone().finalize(public).is_err());
+
+    builder.sign(&secret(2), 2).unwrap();
+    let t = builder.finalize(public).unwrap();
+    assert_eq!(t.hops, [hop(1, 0, 10), hop(2, 0, 10)]);
+}
+
+#[test]
+fn transfer_without_hops_is_not_finalized() {
+    let builder = Transfer::builder();
+    assert!(builder.senders().is_empty());
+    assert!(builder.finalize(public).is_err());
+}
+
+#[test]
+fn hops_are_not_added_after_someone_signed() {
+    let mut builder = Transfer::builder();
+    builder.add_hop(hop(1, 0, 10)).unwrap();
+    let sighash = builder.sighash();
+    builder.sign(&secret(1), 1).unwrap();
+
+    assert!(builder.add_hop(hop(2, 0, 10)).is_err());
+    assert!(builder.add_hops([hop(2, 0, 10)]).is_err());
+    assert_eq!(builder.sighash(), sighash);
+    assert_eq!(builder.finalize(public).unwrap().hops, [hop(1, 0, 10)]);
+}
+
+#[test]
+fn signature_made_with_someone_elses_key_is_not_finalized() {
+    let mut builder = Transfer::builder();
+    builder.add_hops([hop(1, 0, 10), hop(2, 0, 10)]).unwrap();
+    builder.sign(&secret(1), 1).unwrap();
+    // it verifies with the key it comes with, but 2 is not the one that has it
+    builder.sign(&secret(3), 2).unwrap();
+    assert!(builder.missing_signatures().is_empty());
+
+    assert!(builder.clone().finalize(public).is_err());
+    // nor can it be finalized without knowing the keys
+    assert!(builder.finalize(|_| None).is_err());
+}
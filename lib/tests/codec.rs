@@ -0,0 +1,181 @@
+use cassis::test_utils::{hop, secret, signed_transfer};
+use cassis::{DecodeError, Hop, Operation, PeerSig, Transfer, Trust};
+use proptest::prelude::*;
+
+fn encode(op: &Operation) -> Vec<u8> {
+    let mut buf = vec![0; op.size()];
+    op.write_serialized(&mut buf);
+    buf
+}
+
+fn sig() -> impl Strategy<Value = [u8; 64]> {
+    prop::collection::vec(any::<u8>(), 64).prop_map(|bytes| bytes.try_into().unwrap())
+}
+
+fn trust_op() -> impl Strategy<Value = Operation> {
+    (any::<u32>(), any::<u32>(), 0..32u32, any::<u32>(), sig()).prop_map(
+        |(ts, from, to, amount, sig)| {
+            Operation::Trust(Trust {
+                ts,
+                from,
+                to: secret(to).public(),
+                amount,
+                sig,
+            })
+        },
+    )
+}
+
+fn transfer_op() -> impl Strategy<Value = Operation> {
+    let hops = prop::collection::vec(
+        (any::<u32>(), any::<u32>(), any::<u32>()).prop_map(|(from, to, amount)| Hop {
+            from,
+            to,
+            amount,
+        }),
+        0..12,
+    );
+    (any::<u32>(), hops)
+        .prop_flat_map(|(ts, hops)| {
+            let sigs = prop::collection::vec(
+                (any::<u32>(), sig()).prop_map(|(peer_idx, sig)| PeerSig { peer_idx, sig }),
+                0..=hops.len() * 2,
+            );
+            (Just(ts), Just(hops), sigs)
+        })
+        .prop_map(|(ts, hops, sigs)| Operation::Transfer(Transfer { ts, hops, sigs }))
+}
+
+fn operation() -> impl Strategy<Value = Operation> {
+    prop_oneof![trust_op(), transfer_op()]
+}
+
+#[test]
+fn signed_operations_round_trip() {
+    let trust = Operation::Trust(Trust::new(secret(1), 1, secret(2).public(), 500));
+    let transfer = signed_transfer(vec![hop(1, 2, 10), hop(3, 2, 5)]);
+
+    for op in [trust, transfer] {
+        assert_eq!(Operation::try_from(encode(&op).as_slice()), Ok(op));
+    }
+}
+
+#[test]
+fn empty_buffer_is_truncated() {
+    assert_eq!(
+        Operation::try_from(&[][..]),
+        Err(DecodeError::Truncated {
+            offset: 0,
+            needed: 1
+        })
+    );
+}
+
+#[test]
+fn truncation_is_reported_where_the_read_started() {
+    let trust = Operation::Trust(Trust::new(secret(1), 1, secret(2).public(), 500));
+    // the key starts after the tag, ts and from, and is cut 21 bytes short
+    assert_eq!(
+        Operation::try_from(&encode(&trust)[..20]),
+        Err(DecodeError::Truncated {
+            offset: 9,
+            needed: 21
+        })
+    );
+}
+
+#[test]
+fn unknown_tag_is_rejected() {
+    assert_eq!(
+        Operation::try_from(&[b'z', 0, 0][..]),
+        Err(DecodeError::UnknownTag {
+            offset: 0,
+            tag: b'z'
+        })
+    );
+}
+
+#[test]
+fn invalid_public_key_is_rejected() {
+    let op = Operation::Trust(Trust::new(secret(1), 1, secret(2).public(), 500));
+    let mut buf = encode(&op);
+    // no x coordinate this large is on the curve
+    buf[9..41].copy_from_slice(&[0xff; 32]);
+
+    assert_eq!(
+        Operation::try_from(buf.as_slice()),
+        Err(DecodeError::InvalidPublicKey { offset: 9 })
+    );
+}
+
+#[test]
+fn more_signatures_than_peers_are_rejected() {
+    let op = signed_transfer(vec![hop(1, 2, 10)]);
+    let mut buf = encode(&op);
+    // the signature count comes right after the single hop
+    buf[18] = 3;
+
+    assert_eq!(
+        Operation::try_from(buf.as_slice()),
+        Err(DecodeError::TooManySignatures {
+            offset: 18,
+            nsigs: 3
+        })
+    );
+}
+
+proptest! {
+    #[test]
+    fn operations_round_trip(op in operation()) {
+        let buf = encode(&op);
+        prop_assert_eq!(Operation::try_from(buf.as_slice()), Ok(op));
+    }
+
+    #[test]
+    fn every_truncation_is_an_error(op in operation()) {
+        let buf = encode(&op);
+        for len in 0..buf.len() {
+            let result = Operation::try_from(&buf[..len]);
+            prop_assert!(
+                matches!(result, Err(DecodeError::Truncated { offset, needed }) if offset <= len && needed > 0),
+                "{:?}",
+                result
+            );
+        }
+    }
+
+    #[test]
+    fn trailing_bytes_are_rejected(op in operation(), extra in prop::collection::vec(any::<u8>(), 1..8)) {
+        let mut buf = encode(&op);
+        let offset = buf.len();
+        buf.extend_from_slice(&extra);
+        prop_assert_eq!(
+            Operation::try_from(buf.as_slice()),
+            Err(DecodeError::TrailingBytes { offset, extra: extra.len() })
+        );
+    }
+
+    #[test]
+    fn arbitrary_bytes_never_panic(tag in prop::sample::select(vec![b't', b'x', 0]), rest in prop::collection::vec(any::<u8>(), 0..512)) {
+        let mut buf = vec![tag];
+        buf.extend_from_slice(&rest);
+        if let Ok(op) = Operation::try_from(buf.as_slice()) {
+            // whatever decodes must encode back to the same bytes
+            prop_assert_eq!(encode(&op), buf);
+        }
+    }
+}
+
+#[cfg(feature = "redb")]
+#[test]
+fn operations_in_redb_round_trip_or_panic() {
+    use redb::Value;
+
+    let trust = Operation::Trust(Trust::new(secret(1), 1, secret(2).public(), 500));
+    let bytes = Operation::as_bytes(&trust);
+    assert_eq!(Operation::from_bytes(&bytes), trust);
+    assert_eq!(Operation::from_bytes(&[]), Operation::Unknown);
+
+    let broken = std::panic::catch_unwind(|| Operation::from_bytes(&bytes[..20]));
+    assert!(broken.is_err());
+}
@@ -0,0 +1,173 @@
+use cassis::test_utils::{apply, empty_state, hop, signed_transfer, trust};
+use cassis::{
+    state::{process, validate, Line, ValidationError},
+    Operation, Transfer,
+};
+use proptest::prelude::*;
+
+#[test]
+fn trust_from_either_side_sets_its_own_limit() {
+    let mut state = empty_state();
+    apply(&mut state, trust(0, 1, 0));
+    apply(&mut state, trust(0, 2, 0));
+
+    apply(&mut state, trust(1, 2, 30));
+    apply(&mut state, trust(2, 1, 70));
+    apply(&mut state, trust(1, 2, 40));
+
+    let line = &state.lines[&Line::build_key(1, 2)];
+    assert_eq!(line.can_send(2), 40);
+    assert_eq!(line.can_send(1), 70);
+}
+
+#[test]
+fn hop_amount_is_checked_against_credit() {
+    let mut state = empty_state();
+    apply(&mut state, trust(0, 1, 100));
+
+    let too_much = signed_transfer(vec![hop(1, 0, 101)]);
+    assert_eq!(
+        validate(&state, &too_much),
+        Err(ValidationError::InsufficientCredit {
+            hop: 0,
+            line: Line::build_key(0, 1),
+            available: 100
+        })
+    );
+
+    let everything = signed_transfer(vec![hop(1, 0, 100)]);
+    apply(&mut state, everything);
+
+    let one_more = signed_transfer(vec![hop(1, 0, 1)]);
+    assert_eq!(
+        validate(&state, &one_more),
+        Err(ValidationError::InsufficientCredit {
+            hop: 0,
+            line: Line::build_key(0, 1),
+            available: 0
+        })
+    );
+}
+
+#[test]
+fn crossing_the_same_line_twice_cannot_overdraw_it() {
+    let mut state = empty_state();
+    apply(&mut state, trust(0, 1, 100));
+
+    let twice = signed_transfer(vec![hop(1, 0, 60), hop(1, 0, 60)]);
+    assert_eq!(
+        validate(&state, &twice),
+        Err(ValidationError::InsufficientCredit {
+            hop: 1,
+            line: Line::build_key(0, 1),
+            available: 40
+        })
+    );
+}
+
+#[test]
+fn crossing_back_frees_credit_within_the_same_transfer() {
+    let mut state = empty_state();
+    apply(&mut state, trust(0, 1, 100));
+
+    let back_and_forth = signed_transfer(vec![hop(1, 0, 100), hop(0, 1, 50), hop(1, 0, 50)]);
+    apply(&mut state, back_and_forth);
+    assert_eq!(state.lines[&Line::build_key(0, 1)].can_send(1), 0);
+}
+
+#[test]
+fn merged_paths_add_up_shared_lines_and_cancel_opposite_ones() {
+    let paths = vec![
+        vec![hop(3, 2, 30), hop(2, 0, 30)],
+        vec![hop(3, 2, 20), hop(2, 1, 20), hop(1, 0, 20)],
+        vec![hop(1, 2, 20), hop(2, 0, 20)],
+    ];
+    let mut merged = Transfer::merge_paths(&paths).unwrap();
+    merged.sort_by_key(|hop| (hop.from, hop.to));
+    // 2 -> 1 and 1 -> 2 cancel out
+    assert_eq!(merged, [hop(1, 0, 20), hop(2, 0, 50), hop(3, 2, 50)]);
+
+    let mut state = empty_state();
+    apply(&mut state, trust(0, 1, 100));
+    apply(&mut state, trust(0, 2, 100));
+    apply(&mut state, trust(2, 3, 50));
+    apply(&mut state, signed_transfer(merged));
+    assert_eq!(state.lines[&Line::build_key(2, 3)].can_send(3), 0);
+}
+
+#[test]
+fn merged_paths_that_add_up_to_more_than_a_hop_can_carry_are_refused() {
+    let paths = vec![vec![hop(1, 0, u32::MAX)], vec![hop(1, 2, 1), hop(2, 0, 1)]];
+    assert!(Transfer::merge_paths(&paths).is_ok());
+
+    let paths = vec![vec![hop(1, 0, u32::MAX)], vec![hop(1, 0, 1)]];
+    assert!(Transfer::merge_paths(&paths).is_err());
+    // unless the other direction takes it back under
+    let paths = vec![
+        vec![hop(1, 0, u32::MAX)],
+        vec![hop(1, 0, 1)],
+        vec![hop(0, 1, 1)],
+    ];
+    assert_eq!(
+        Transfer::merge_paths(&paths).unwrap(),
+        [hop(1, 0, u32::MAX)]
+    );
+}
+
+const NKEYS: u32 = 5;
+
+#[derive(Debug, Clone)]
+enum Action {
+    Trust { from: u32, to: u32, amount: u32 },
+    Transfer { hops: Vec<(u32, u32, u32)> },
+}
+
+fn action() -> impl Strategy<Value = Action> {
+    prop_oneof![
+        (0..NKEYS, 0..NKEYS, 0..200u32).prop_map(|(from, to, amount)| Action::Trust {
+            from,
+            to,
+            amount
+        }),
+        prop::collection::vec((0..NKEYS, 0..NKEYS, 1..150u32), 1..5)
+            .prop_map(|hops| Action::Transfer { hops }),
+    ]
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(64))]
+
+    // trust can be lowered below what is already owed, so the invariant is that a transfer never
+    // moves a balance further past the trust than it already was
+    #[test]
+    fn balances_never_go_beyond_trust(actions in prop::collection::vec(action(), 1..60)) {
+        let mut state = empty_state();
+        for i in 1..NKEYS {
+            apply(&mut state, trust(0, i, 0));
+        }
+
+        for action in actions {
+            let op = match action {
+                Action::Trust { from, to, amount } => trust(from, to, amount),
+                Action::Transfer { hops } => signed_transfer(
+                    hops.into_iter().map(|(from, to, amount)| hop(from, to, amount)).collect(),
+                ),
+            };
+
+            let before = state.lines.clone();
+            if validate(&state, &op).is_err() {
+                continue;
+            }
+            process(&mut state, &op);
+
+            if let Operation::Transfer(t) = &op {
+                for hop in t.hops.iter() {
+                    let key = Line::build_key(hop.from, hop.to);
+                    let (old, new) = (&before[&key], &state.lines[&key]);
+                    prop_assert!(new.balance <= old.balance.max(new.trust.0 as i64));
+                    prop_assert!(new.balance >= old.balance.min(-(new.trust.1 as i64)));
+                }
+            }
+        }
+    }
+}
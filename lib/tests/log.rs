@@ -0,0 +1,75 @@
+use cassis::log::{chain, Entry, Head, GENESIS};
+use cassis::test_utils::{secret, trust};
+
+#[test]
+fn head_signed_by_registry_verifies() {
+    let head = Head::sign(&secret(0), 7, [3; 32], [5; 32], [9; 32]);
+    assert!(head.verify(&secret(0).public()).is_ok());
+    assert!(head.verify(&secret(1).public()).is_err());
+}
+
+#[test]
+fn head_with_tampered_fields_does_not_verify() {
+    let head = Head::sign(&secret(0), 7, [3; 32], [5; 32], [9; 32]);
+
+    let mut idx = head.clone();
+    idx.idx = 8;
+    let mut hash = head.clone();
+    hash.hash[0] = 4;
+    let mut root = head.clone();
+    root.root[0] = 4;
+    let mut state_root = head.clone();
+    state_root.state_root[0] = 4;
+
+    for tampered in [idx, hash, root, state_root] {
+        assert!(tampered.verify(&secret(0).public()).is_err());
+    }
+}
+
+#[test]
+fn chain_commits_to_every_entry_and_their_order() {
+    let ops = vec![trust(0, 1, 10), trust(0, 2, 20), trust(1, 2, 30)];
+    let full = chain(GENESIS, &ops);
+
+    // can be computed incrementally
+    assert_eq!(chain(chain(GENESIS, &ops[..1]), &ops[1..]), full);
+
+    let mut changed = ops.clone();
+    changed[0] = trust(0, 1, 11);
+    assert_ne!(chain(GENESIS, &changed), full);
+
+    let mut reordered = ops.clone();
+    reordered.swap(0, 1);
+    assert_ne!(chain(GENESIS, &reordered), full);
+
+    assert_ne!(chain(GENESIS, &ops[..2]), full);
+}
+
+#[test]
+fn entries_follow_each_other_through_the_chain() {
+    let ops = [trust(0, 1, 10), trust(0, 2, 20), trust(1, 2, 30)];
+    let entries: Vec<Entry> = ops
+        .iter()
+        .enumerate()
+        .map(|(idx, op)| Entry {
+            idx: idx as u32,
+            hash: chain(GENESIS, &ops[..=idx]),
+            op: op.clone(),
+        })
+        .collect();
+
+    assert!(entries[0].follows(&GENESIS));
+    assert!(entries[1].follows(&entries[0].hash));
+    assert!(entries[2].follows(&entries[1].hash));
+
+    // skipped, repeated or changed entries don't
+    assert!(!entries[2].follows(&entries[0].hash));
+    assert!(!entries[1].follows(&entries[1].hash));
+    let mut changed = entries[1].clone();
+    changed.op = trust(0, 2, 21);
+    assert!(!changed.follows(&entries[0].hash));
+
+    let json = serde_json::to_value(&entries[0]).unwrap();
+    assert_eq!(json["hash"], hex::encode(entries[0].hash));
+    assert_eq!(serde_json::from_value::<Entry>(json).unwrap(), entries[0]);
+}
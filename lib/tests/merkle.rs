@@ -0,0 +1,205 @@
+use cassis::test_utils::{secret, trust};
+use cassis::{
+    log::{entry_hash, ConsistencyProof, Head, InclusionProof},
+    merkle::{leaf_hash, node_hash, verify_consistency, verify_inclusion, Tree},
+};
+
+fn entry(i: u32) -> [u8; 32] {
+    let mut hash = [0u8; 32];
+    hash[0..4].copy_from_slice(&i.to_le_bytes());
+    hash
+}
+
+// the tree hash straight from its definition in RFC 6962
+fn reference_root(entries: &[[u8; 32]]) -> [u8; 32] {
+    if entries.len() == 1 {
+        return leaf_hash(&entries[0]);
+    }
+    let k = entries.len().next_power_of_two() / 2;
+    node_hash(
+        &reference_root(&entries[..k]),
+        &reference_root(&entries[k..]),
+    )
+}
+
+fn tree(size: u32) -> Tree {
+    let mut tree = Tree::new();
+    for i in 0..size {
+        tree.push(&entry(i));
+    }
+    tree
+}
+
+#[test]
+fn roots_match_the_definition_at_every_size() {
+    let entries: Vec<[u8; 32]> = (0..70).map(entry).collect();
+    let tree = tree(70);
+    for size in 1..=70 {
+        assert_eq!(
+            tree.root_at(size as u64),
+            Some(reference_root(&entries[..size])),
+            "size {}",
+            size
+        );
+    }
+    assert_eq!(tree.root_at(71), None);
+}
+
+#[test]
+fn every_leaf_is_proven_in_every_tree_that_has_it() {
+    let tree = tree(40);
+    for size in 1..=40u64 {
+        let root = tree.root_at(size).unwrap();
+        for idx in 0..size {
+            let proof = tree.inclusion_proof(idx, size).unwrap();
+            assert!(
+                verify_inclusion(&entry(idx as u32), idx, size, &proof, &root),
+                "leaf {} in tree of size {}",
+                idx,
+                size
+            );
+        }
+    }
+}
+
+#[test]
+fn wrong_leaf_position_or_proof_fails() {
+    let tree = tree(21);
+    let root = tree.root();
+    let proof = tree.inclusion_proof(13, 21).unwrap();
+
+    assert!(!verify_inclusion(&entry(12), 13, 21, &proof, &root));
+    assert!(!verify_inclusion(&entry(13), 12, 21, &proof, &root));
+    assert!(!verify_inclusion(&entry(13), 13, 16, &proof, &root));
+    assert!(!verify_inclusion(&entry(13), 13, 21, &proof[1..], &root));
+
+    let mut extra = proof.clone();
+    extra.push([0; 32]);
+    assert!(!verify_inclusion(&entry(13), 13, 21, &extra, &root));
+
+    let mut tampered = proof.clone();
+    tampered[2][0] ^= 1;
+    assert!(!verify_inclusion(&entry(13), 13, 21, &tampered, &root));
+
+    assert_eq!(tree.inclusion_proof(21, 21), None);
+    assert_eq!(tree.inclusion_proof(0, 22), None);
+}
+
+#[test]
+fn operation_is_proven_against_signed_head() {
+    let ops = [trust(0, 1, 10), trust(0, 2, 20), trust(1, 2, 30)];
+    let mut tree = Tree::new();
+    for op in ops.iter() {
+        tree.push(&entry_hash(op));
+    }
+
+    let registry = secret(0);
+    let head = Head::sign(&registry, 2, [9; 32], tree.root(), [0; 32]);
+    let proof = InclusionProof {
+        idx: 1,
+        path: tree.inclusion_proof(1, 3).unwrap(),
+        head,
+    };
+
+    assert!(proof.verify(&ops[1], &registry.public()).is_ok());
+    assert!(proof.verify(&ops[0], &registry.public()).is_err());
+    assert!(proof.verify(&ops[1], &secret(1).public()).is_err());
+
+    // the proof survives being sent around as json
+    let json = serde_json::to_string(&proof).unwrap();
+    let decoded: InclusionProof = serde_json::from_str(&json).unwrap();
+    assert_eq!(decoded, proof);
+}
+
+#[test]
+fn every_tree_is_consistent_with_all_before_it() {
+    let tree = tree(40);
+    for to in 1..=40u64 {
+        let new_root = tree.root_at(to).unwrap();
+        for from in 1..=to {
+            let old_root = tree.root_at(from).unwrap();
+            let proof = tree.consistency_proof(from, to).unwrap();
+            assert!(
+                verify_consistency(from, to, &proof, &old_root, &new_root),
+                "from {} to {}",
+                from,
+                to
+            );
+        }
+    }
+    assert_eq!(tree.consistency_proof(0, 10), None);
+    assert_eq!(tree.consistency_proof(11, 10), None);
+    assert_eq!(tree.consistency_proof(10, 41), None);
+}
+
+#[test]
+fn rewritten_history_is_not_consistent() {
+    let original = tree(13);
+    let old_root = original.root_at(7).unwrap();
+
+    // same size, but one of the first seven entries is different
+    let mut forked = Tree::new();
+    for i in 0..13 {
+        forked.push(&entry(if i == 4 { 1000 } else { i }));
+    }
+    let proof = forked.consistency_proof(7, 13).unwrap();
+    assert!(!verify_consistency(
+        7,
+        13,
+        &proof,
+        &old_root,
+        &forked.root()
+    ));
+
+    // the honest proof doesn't work for other sizes or roots either
+    let proof = original.consistency_proof(7, 13).unwrap();
+    assert!(verify_consistency(
+        7,
+        13,
+        &proof,
+        &old_root,
+        &original.root()
+    ));
+    assert!(!verify_consistency(
+        6,
+        13,
+        &proof,
+        &old_root,
+        &original.root()
+    ));
+    assert!(!verify_consistency(
+        7,
+        13,
+        &proof,
+        &old_root,
+        &forked.root()
+    ));
+    assert!(!verify_consistency(
+        7,
+        13,
+        &proof[1..],
+        &old_root,
+        &original.root()
+    ));
+}
+
+#[test]
+fn consistency_is_checked_between_signed_heads() {
+    let registry = secret(0);
+    let tree = tree(9);
+    let old = Head::sign(&registry, 4, [1; 32], tree.root_at(5).unwrap(), [0; 32]);
+    let new = Head::sign(&registry, 8, [2; 32], tree.root_at(9).unwrap(), [0; 32]);
+    let proof = ConsistencyProof {
+        from: 5,
+        to: 9,
+        path: tree.consistency_proof(5, 9).unwrap(),
+    };
+
+    assert!(proof.verify(&old, &new, &registry.public()).is_ok());
+    assert!(proof.verify(&new, &old, &registry.public()).is_err());
+    assert!(proof.verify(&old, &new, &secret(1).public()).is_err());
+
+    let mut forged = new.clone();
+    forged.root = tree.root_at(8).unwrap();
+    assert!(proof.verify(&old, &forged, &registry.public()).is_err());
+}
@@ -0,0 +1,199 @@
+use cassis::test_utils::{apply, empty_state, hop, secret, signed_transfer, trust};
+use cassis::{
+    log::{Head, LineProof},
+    merkle,
+    state::{
+        process,
+        root::{leaf_hash, state_root, verify_line, LineTree, EMPTY},
+        Line, StateTree,
+    },
+    Operation, State,
+};
+
+// the sparse tree straight from its definition, without any of the caching
+fn reference_lines_root(state: &State) -> [u8; 32] {
+    fn subtree(depth: u32, leaves: &[(u64, [u8; 32])]) -> [u8; 32] {
+        match leaves {
+            [] => EMPTY,
+            [(_, hash)] => *hash,
+            _ => {
+                let left: Vec<_> = leaves
+                    .iter()
+                    .filter(|(key, _)| (key >> (63 - depth)) & 1 == 0)
+                    .cloned()
+                    .collect();
+                let right: Vec<_> = leaves[left.len()..].to_vec();
+                merkle::node_hash(&subtree(depth + 1, &left), &subtree(depth + 1, &right))
+            }
+        }
+    }
+
+    let mut leaves: Vec<(u64, [u8; 32])> = state
+        .lines
+        .iter()
+        .map(|(key, line)| (*key, leaf_hash(line)))
+        .collect();
+    leaves.sort();
+    subtree(0, &leaves)
+}
+
+fn ops() -> Vec<Operation> {
+    vec![
+        trust(0, 1, 100),
+        trust(0, 2, 50),
+        trust(1, 2, 30),
+        trust(2, 1, 20),
+        trust(0, 3, 10),
+        signed_transfer(vec![hop(1, 0, 40), hop(2, 1, 10)]),
+        trust(3, 4, 70),
+        trust(0, 1, 120),
+        signed_transfer(vec![hop(3, 0, 5)]),
+    ]
+}
+
+#[test]
+fn updated_root_matches_root_from_scratch() {
+    let mut state = empty_state();
+    let mut tree = StateTree::new(&state);
+    assert_eq!(tree.root(), state.root());
+
+    for op in ops() {
+        apply(&mut state, op.clone());
+        tree.update(&state, &op);
+
+        assert_eq!(tree.root(), state.root());
+        assert_eq!(tree.lines_root(), reference_lines_root(&state));
+        assert_eq!(
+            tree.root(),
+            state_root(&tree.keys_root(), &tree.lines_root())
+        );
+    }
+}
+
+#[test]
+fn lines_root_does_not_depend_on_insertion_order() {
+    let mut state = empty_state();
+    for op in ops() {
+        apply(&mut state, op);
+    }
+
+    let mut lines: Vec<&Line> = state.lines.values().collect();
+    lines.sort_by_key(|line| line.peers);
+    let mut forward = LineTree::default();
+    let mut backward = LineTree::default();
+    for line in lines.iter() {
+        forward.update(line);
+    }
+    for line in lines.iter().rev() {
+        backward.update(line);
+    }
+
+    assert_eq!(forward.len(), state.lines.len());
+    assert_eq!(forward.root(), backward.root());
+    assert_eq!(forward.root(), LineTree::new(state.lines.values()).root());
+    assert_eq!(LineTree::default().root(), EMPTY);
+}
+
+#[test]
+fn root_commits_to_every_key_and_line_field() {
+    let mut state = empty_state();
+    for op in ops() {
+        apply(&mut state, op);
+    }
+    let root = state.root();
+
+    let key = *state.lines.keys().next().unwrap();
+    for change in 0..4 {
+        let mut changed = state.clone();
+        let line = changed.lines.get_mut(&key).unwrap();
+        match change {
+            0 => line.trust.0 += 1,
+            1 => line.trust.1 += 1,
+            2 => line.balance -= 1,
+            _ => line.peers = (line.peers.0, line.peers.1 + 1),
+        }
+        assert_ne!(changed.root(), root, "change {}", change);
+    }
+
+    let mut swapped = state.clone();
+    swapped.keys.swap(1, 2);
+    assert_ne!(swapped.root(), root);
+
+    // a trust that changes nothing gives the same root
+    let mut same = state.clone();
+    let op = trust(0, 1, 120);
+    apply(&mut same, op.clone());
+    let mut tree = StateTree::new(&state);
+    tree.update(&same, &op);
+    assert_eq!(tree.root(), root);
+
+    process(&mut same, &trust(0, 1, 121));
+    assert_ne!(same.root(), root);
+}
+
+#[test]
+fn every_line_proves_against_the_lines_root() {
+    let mut state = empty_state();
+    let mut tree = LineTree::default();
+    for op in ops() {
+        apply(&mut state, op);
+        for line in state.lines.values() {
+            tree.update(line);
+        }
+
+        for line in state.lines.values() {
+            let path = tree.proof(line.peers.1, line.peers.0).unwrap();
+            assert!(verify_line(line, &path, &tree.root()));
+
+            let mut lying = line.clone();
+            lying.balance += 1;
+            assert!(!verify_line(&lying, &path, &tree.root()));
+
+            let mut swapped = line.clone();
+            swapped.peers = (line.peers.1, line.peers.0);
+            assert!(!verify_line(&swapped, &path, &tree.root()));
+
+            if !path.is_empty() {
+                assert!(!verify_line(line, &path[1..], &tree.root()));
+            }
+        }
+    }
+    assert!(tree.proof(0, 4).is_none());
+}
+
+#[test]
+fn line_proof_verifies_against_signed_head() {
+    let mut state = empty_state();
+    for op in ops() {
+        apply(&mut state, op);
+    }
+    let tree = StateTree::new(&state);
+    let head = Head::sign(&secret(0), 8, [1; 32], [2; 32], tree.root());
+
+    let proof = LineProof {
+        line: state.lines[&Line::build_key(0, 1)].clone(),
+        keys_root: tree.keys_root(),
+        path: tree.line_proof(0, 1).unwrap(),
+        head: head.clone(),
+    };
+    assert!(proof.verify(&secret(0).public()).is_ok());
+    assert!(proof.verify(&secret(1).public()).is_err());
+
+    let json = serde_json::to_string(&proof).unwrap();
+    assert_eq!(serde_json::from_str::<LineProof>(&json).unwrap(), proof);
+
+    let mut lying = proof.clone();
+    lying.line.trust.1 += 1;
+    assert!(lying.verify(&secret(0).public()).is_err());
+
+    let mut other_keys = proof.clone();
+    other_keys.keys_root[0] ^= 1;
+    assert!(other_keys.verify(&secret(0).public()).is_err());
+
+    // the same line is no longer proven once the state moves on
+    let mut later = state.clone();
+    process(&mut later, &trust(0, 1, 1));
+    let mut stale = proof.clone();
+    stale.head = Head::sign(&secret(0), 9, [1; 32], [2; 32], later.root());
+    assert!(stale.verify(&secret(0).public()).is_err());
+}
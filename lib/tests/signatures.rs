@@ -0,0 +1,244 @@
+use cassis::test_utils::{apply, empty_state, hop, secret, transfer_signed_by, trust};
+use cassis::{
+    state::{validate, ValidationError},
+    Operation, OperationOps, PeerSig, State, Transfer, Trust,
+};
+
+// key 0 is the registry and trusts 1, 2 and 3, so they can all pay it. 2 trusts 1 and 3 trusts 2,
+// so 1 can also pay 3 through 2.
+fn setup() -> State {
+    let mut state = empty_state();
+    for i in 1..4 {
+        apply(&mut state, trust(0, i, 1000));
+    }
+    for (from, to) in [(2, 1), (3, 2)] {
+        apply(&mut state, trust(from, to, 1000));
+    }
+    state
+}
+
+#[test]
+fn valid_trust_is_accepted() {
+    let state = setup();
+    let op = Operation::Trust(Trust::new(secret(3), 3, secret(1).public(), 50));
+    assert_eq!(validate(&state, &op), Ok(()));
+}
+
+#[test]
+fn trust_signed_by_wrong_key_is_rejected() {
+    let state = setup();
+    let op = Operation::Trust(Trust::new(secret(2), 3, secret(1).public(), 50));
+    assert_eq!(
+        validate(&state, &op),
+        Err(ValidationError::BadSignature { idx: 3 })
+    );
+}
+
+#[test]
+fn trust_with_tampered_fields_is_rejected() {
+    let state = setup();
+    let original = Trust::new(secret(3), 3, secret(1).public(), 50);
+
+    let mut amount = original.clone();
+    amount.amount = 5000;
+    let mut to = original.clone();
+    to.to = secret(2).public();
+    let mut ts = original.clone();
+    ts.ts += 1;
+    let mut from = original.clone();
+    from.from = 2;
+
+    for (tampered, idx) in [(amount, 3), (to, 3), (ts, 3), (from, 2)] {
+        assert_eq!(
+            validate(&state, &Operation::Trust(tampered)),
+            Err(ValidationError::BadSignature { idx })
+        );
+    }
+}
+
+#[test]
+fn trust_with_zeroed_signature_is_rejected() {
+    let state = setup();
+    let mut trust = Trust::new(secret(3), 3, secret(1).public(), 50);
+    trust.sig = [0; 64];
+    assert_eq!(
+        validate(&state, &Operation::Trust(trust)),
+        Err(ValidationError::BadSignature { idx: 3 })
+    );
+}
+
+#[test]
+fn valid_transfer_is_accepted() {
+    let state = setup();
+    let t = transfer_signed_by(vec![hop(1, 2, 10), hop(2, 3, 10)], &[1]);
+    assert_eq!(validate(&state, &Operation::Transfer(t)), Ok(()));
+}
+
+#[test]
+fn transfer_signed_by_wrong_key_is_rejected() {
+    let state = setup();
+    let mut t = Transfer {
+        ts: 1,
+        hops: vec![hop(1, 2, 10)],
+        sigs: vec![],
+    };
+    t.sigs.push(PeerSig {
+        peer_idx: 1,
+        sig: secret(2).sign(t.sighash()),
+    });
+    assert_eq!(
+        validate(&state, &Operation::Transfer(t)),
+        Err(ValidationError::BadSignature { idx: 1 })
+    );
+}
+
+#[test]
+fn transfer_with_tampered_fields_is_rejected() {
+    let state = setup();
+    let original = transfer_signed_by(vec![hop(1, 2, 10), hop(2, 3, 10)], &[1]);
+
+    let mut amount = original.clone();
+    amount.hops[0].amount = 9;
+    amount.hops[1].amount = 9;
+    let mut destination = original.clone();
+    destination.hops[1].to = 0;
+    destination.hops[1].from = 2;
+    let mut ts = original.clone();
+    ts.ts += 1;
+    let mut extra_hop = original.clone();
+    extra_hop.hops.push(hop(1, 0, 1));
+
+    for tampered in [amount, destination, ts, extra_hop] {
+        assert_eq!(
+            validate(&state, &Operation::Transfer(tampered)),
+            Err(ValidationError::BadSignature { idx: 1 })
+        );
+    }
+}
+
+#[test]
+fn transfer_with_swapped_signatures_is_rejected() {
+    let state = setup();
+    let mut t = transfer_signed_by(vec![hop(1, 0, 10), hop(2, 0, 10)], &[1, 2]);
+    let (first, second) = (t.sigs[0].sig, t.sigs[1].sig);
+    t.sigs[0].sig = second;
+    t.sigs[1].sig = first;
+
+    assert!(matches!(
+        validate(&state, &Operation::Transfer(t)),
+        Err(ValidationError::BadSignature { .. })
+    ));
+}
+
+#[test]
+fn transfer_with_signature_from_another_transfer_is_rejected() {
+    let state = setup();
+    let other = transfer_signed_by(vec![hop(1, 2, 10)], &[1]);
+    let mut t = Transfer {
+        ts: other.ts,
+        hops: vec![hop(1, 2, 20)],
+        sigs: vec![],
+    };
+    t.sigs = other.sigs.clone();
+
+    assert_eq!(
+        validate(&state, &Operation::Transfer(t)),
+        Err(ValidationError::BadSignature { idx: 1 })
+    );
+}
+
+#[test]
+fn transfer_with_signature_attributed_to_unknown_key_is_rejected() {
+    let state = setup();
+    let mut t = transfer_signed_by(vec![hop(1, 2, 10)], &[1]);
+    t.sigs.push(PeerSig {
+        peer_idx: 77,
+        sig: t.sigs[0].sig,
+    });
+
+    assert_eq!(
+        validate(&state, &Operation::Transfer(t)),
+        Err(ValidationError::UnknownKey { idx: 77 })
+    );
+}
+
+#[test]
+fn transfer_without_sender_signature_is_rejected() {
+    let state = setup();
+    let mut t = transfer_signed_by(vec![hop(1, 0, 10), hop(2, 0, 10)], &[1, 2]);
+    t.sigs.remove(1);
+
+    assert_eq!(
+        validate(&state, &Operation::Transfer(t)),
+        Err(ValidationError::MissingSigner { idx: 2 })
+    );
+}
+
+#[test]
+fn transfer_signed_more_than_once_by_the_same_key_is_rejected() {
+    let state = setup();
+    let mut t = transfer_signed_by(vec![hop(1, 0, 10)], &[1]);
+    t.sigs.push(t.sigs[0].clone());
+    t.sigs.push(t.sigs[0].clone());
+
+    // three couldn't even be decoded for a single hop
+    assert_eq!(
+        validate(&state, &Operation::Transfer(t.clone())),
+        Err(ValidationError::TooManySignatures { count: 3 })
+    );
+    t.sigs.truncate(2);
+    assert_eq!(
+        validate(&state, &Operation::Transfer(t)),
+        Err(ValidationError::DuplicateSigner { idx: 1 })
+    );
+}
+
+#[test]
+fn transfer_with_more_signatures_than_peers_is_rejected() {
+    let state = setup();
+    let mut t = transfer_signed_by(vec![hop(1, 0, 10)], &[1]);
+    for idx in [2, 3] {
+        t.sigs.push(PeerSig {
+            peer_idx: idx,
+            sig: secret(idx).sign(t.sighash()),
+        });
+    }
+
+    assert_eq!(
+        validate(&state, &Operation::Transfer(t)),
+        Err(ValidationError::TooManySignatures { count: 3 })
+    );
+}
+
+#[test]
+fn transfer_with_more_hops_than_can_be_encoded_is_rejected() {
+    let state = setup();
+    // goes back and forth, so nobody sends anything in the end and nobody has to sign
+    let t = Transfer {
+        ts: 1,
+        hops: (0..128)
+            .flat_map(|_| [hop(1, 0, 1), hop(0, 1, 1)])
+            .collect(),
+        sigs: vec![],
+    };
+
+    assert_eq!(
+        validate(&state, &Operation::Transfer(t)),
+        Err(ValidationError::TooManyHops { count: 256 })
+    );
+}
+
+#[test]
+fn transfer_without_hops_is_rejected() {
+    let state = setup();
+    let t = Transfer {
+        ts: 1,
+        hops: vec![],
+        sigs: vec![],
+    };
+
+    assert_eq!(
+        validate(&state, &Operation::Transfer(t)),
+        Err(ValidationError::EmptyTransfer)
+    );
+}
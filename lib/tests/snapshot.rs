@@ -0,0 +1,110 @@
+use cassis::test_utils::{apply, empty_state, hop, signed_transfer, trust};
+use cassis::{
+    state::{Line, SnapshotError},
+    State,
+};
+
+fn some_state() -> State {
+    let mut state = empty_state();
+    apply(&mut state, trust(0, 1, 100));
+    apply(&mut state, trust(0, 2, 50));
+    apply(&mut state, trust(1, 2, 30));
+    apply(&mut state, trust(2, 1, 20));
+    apply(
+        &mut state,
+        signed_transfer(vec![hop(1, 0, 40), hop(2, 1, 10)]),
+    );
+    state
+}
+
+fn bytes(state: &State) -> Vec<u8> {
+    let mut buf = Vec::new();
+    state.write_to(&mut buf).unwrap();
+    buf
+}
+
+#[test]
+fn state_round_trips() {
+    let state = some_state();
+    let buf = bytes(&state);
+    assert_eq!(State::read_from(buf.as_slice()).unwrap(), state);
+
+    let empty = empty_state();
+    assert_eq!(State::read_from(bytes(&empty).as_slice()).unwrap(), empty);
+}
+
+#[test]
+fn same_state_gives_same_bytes() {
+    let state = some_state();
+
+    // the same lines inserted in another order
+    let mut shuffled = state.clone();
+    let mut lines: Vec<(u64, Line)> = shuffled.lines.drain().collect();
+    lines.reverse();
+    shuffled.lines.extend(lines);
+    assert_eq!(bytes(&shuffled), bytes(&state));
+
+    let mut changed = state.clone();
+    changed.lines.values_mut().next().unwrap().balance += 1;
+    assert_ne!(bytes(&changed), bytes(&state));
+}
+
+#[test]
+fn reads_one_snapshot_and_leaves_the_rest() {
+    let first = some_state();
+    let second = empty_state();
+    let mut buf = bytes(&first);
+    buf.extend(bytes(&second));
+
+    let mut reader = buf.as_slice();
+    assert_eq!(State::read_from(&mut reader).unwrap(), first);
+    assert_eq!(State::read_from(&mut reader).unwrap(), second);
+    assert!(reader.is_empty());
+}
+
+#[test]
+fn broken_snapshots_are_rejected() {
+    let buf = bytes(&some_state());
+
+    for at in 0..buf.len() {
+        let mut corrupted = buf.clone();
+        corrupted[at] ^= 1;
+        assert!(
+            State::read_from(corrupted.as_slice()).is_err(),
+            "flipped byte {}",
+            at
+        );
+    }
+    for len in 0..buf.len() {
+        assert!(
+            matches!(State::read_from(&buf[..len]), Err(SnapshotError::Io(_))),
+            "cut at {}",
+            len
+        );
+    }
+
+    let mut magic = buf.clone();
+    magic[0] = b'x';
+    assert!(matches!(
+        State::read_from(magic.as_slice()),
+        Err(SnapshotError::BadMagic)
+    ));
+
+    let mut version = buf.clone();
+    version[4] = 2;
+    assert!(matches!(
+        State::read_from(version.as_slice()),
+        Err(SnapshotError::UnsupportedVersion { version: 2 })
+    ));
+}
+
+#[test]
+fn state_goes_through_serde() {
+    let state = some_state();
+    let json = serde_json::to_string(&state).unwrap();
+    assert_eq!(json, format!("\"{}\"", hex::encode(bytes(&state))));
+    assert_eq!(serde_json::from_str::<State>(&json).unwrap(), state);
+
+    let trailing = format!("\"{}00\"", hex::encode(bytes(&state)));
+    assert!(serde_json::from_str::<State>(&trailing).is_err());
+}
@@ -0,0 +1,162 @@
+use cassis::{state::Line, Operation, PublicKey, State};
+use std::collections::BTreeSet;
+
+// which keys each key has a line with, so looking up one key doesn't go through every line
+#[derive(Debug, Default)]
+pub struct Adjacency {
+    peers: Vec<BTreeSet<u32>>,
+}
+
+impl Adjacency {
+    pub fn new(state: &State) -> Self {
+        let mut adjacency = Adjacency {
+            peers: vec![BTreeSet::new(); state.keys.len()],
+        };
+        for line in state.lines.values() {
+            adjacency.add(line.peers.0, line.peers.1);
+        }
+        adjacency
+    }
+
+    fn add(&mut self, a: u32, b: u32) {
+        let needed = a.max(b) as usize + 1;
+        if self.peers.len() < needed {
+            self.peers.resize(needed, BTreeSet::new());
+        }
+        self.peers[a as usize].insert(b);
+        self.peers[b as usize].insert(a);
+    }
+
+    // catches up with `state` after `op` was processed on it, only trusts create lines
+    pub fn update(&mut self, state: &State, op: &Operation) {
+        if let Operation::Trust(t) = op {
+            self.add(t.from, state.key_indexes[&t.to.serialize()]);
+        }
+    }
+
+    pub fn peers(&self, idx: u32) -> impl Iterator<Item = u32> + '_ {
+        self.peers
+            .get(idx as usize)
+            .into_iter()
+            .flat_map(|peers| peers.iter().copied())
+    }
+}
+
+// a line as seen by one of its peers
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+pub struct AccountLine {
+    pub peer: u32,
+    pub peer_key: PublicKey,
+    // how much we trust the peer and the peer trusts us
+    pub trust_given: u32,
+    pub trust_received: u32,
+    // how much the peer owes us, negative when we owe the peer
+    pub balance: i64,
+    // how much we can still send to the peer through this line
+    pub can_send: i64,
+}
+
+// everything about a key: its lines with the totals over all of them
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+pub struct Account {
+    pub idx: u32,
+    pub key: PublicKey,
+    pub lines: Vec<AccountLine>,
+    // how much everybody owes us minus how much we owe everybody
+    pub balance: i64,
+    pub credit_extended: u64,
+    pub credit_received: u64,
+}
+
+impl Account {
+    pub fn build(state: &State, adjacency: &Adjacency, idx: u32) -> Option<Self> {
+        let key = *state.keys.get(idx as usize)?;
+        let lines: Vec<AccountLine> = adjacency
+            .peers(idx)
+            .map(|peer| {
+                let line = &state.lines[&Line::build_key(idx, peer)];
+                // a positive balance is what the first peer owes the second
+                let (trust_given, trust_received, balance) = if line.peers.0 == idx {
+                    (line.trust.1, line.trust.0, -line.balance)
+                } else {
+                    (line.trust.0, line.trust.1, line.balance)
+                };
+
+                AccountLine {
+                    peer,
+                    peer_key: state.keys[peer as usize],
+                    trust_given,
+                    trust_received,
+                    balance,
+                    can_send: line.can_send(idx),
+                }
+            })
+            .collect();
+
+        Some(Account {
+            idx,
+            key,
+            balance: lines.iter().map(|line| line.balance).sum(),
+            credit_extended: lines.iter().map(|line| line.trust_given as u64).sum(),
+            credit_received: lines.iter().map(|line| line.trust_received as u64).sum(),
+            lines,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cassis::test_utils::{empty_state, hop, signed_transfer, trust};
+
+    #[test]
+    fn account_is_seen_from_the_key_side() {
+        let mut state = empty_state();
+        let mut adjacency = Adjacency::new(&state);
+
+        let ops = vec![
+            trust(0, 1, 100),
+            trust(0, 2, 50),
+            trust(1, 0, 10),
+            trust(2, 3, 5),
+            signed_transfer(vec![hop(1, 0, 30)]),
+        ];
+        for op in ops {
+            cassis::state::validate(&state, &op).unwrap();
+            cassis::state::process(&mut state, &op);
+            adjacency.update(&state, &op);
+        }
+
+        // the same as building it from the whole state
+        let built = Adjacency::new(&state);
+        for idx in 0..state.keys.len() as u32 {
+            assert!(adjacency.peers(idx).eq(built.peers(idx)));
+        }
+
+        let zero = Account::build(&state, &adjacency, 0).unwrap();
+        assert_eq!(
+            zero.lines.iter().map(|l| l.peer).collect::<Vec<_>>(),
+            [1, 2]
+        );
+        assert_eq!(zero.lines[0].trust_given, 100);
+        assert_eq!(zero.lines[0].trust_received, 10);
+        assert_eq!(zero.lines[0].balance, 30);
+        assert_eq!(zero.lines[0].can_send, 40);
+        assert_eq!(zero.balance, 30);
+        assert_eq!(zero.credit_extended, 150);
+        assert_eq!(zero.credit_received, 10);
+
+        let one = Account::build(&state, &adjacency, 1).unwrap();
+        assert_eq!(one.lines.len(), 1);
+        assert_eq!(one.lines[0].peer_key, state.keys[0]);
+        assert_eq!(one.lines[0].trust_given, 10);
+        assert_eq!(one.lines[0].trust_received, 100);
+        assert_eq!(one.lines[0].balance, -30);
+        assert_eq!(one.lines[0].can_send, 70);
+        assert_eq!(one.balance, -30);
+
+        let three = Account::build(&state, &adjacency, 3).unwrap();
+        assert_eq!(three.credit_received, 5);
+        assert!(Account::build(&state, &adjacency, 4).is_none());
+    }
+}
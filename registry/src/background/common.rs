@@ -0,0 +1,28 @@
+// fixtures shared by the tests in this crate, on top of the ones in `cassis::test_utils`
+use cassis::{test_utils::secret, SecretKey};
+use std::path::Path;
+
+use super::db::{Durability, LogStore};
+use super::{Config, Requester};
+
+// opened and healed, ready to be appended to
+pub fn open_store(path: &Path, durability: Durability) -> LogStore {
+    let mut ls = LogStore::init(path, durability).unwrap();
+    ls.check_and_heal().unwrap();
+    ls
+}
+
+// keeping the log in `path`, syncing every append
+pub fn config(path: &Path, snapshot_interval: u32) -> Config {
+    Config {
+        path: path.to_path_buf(),
+        durability: Durability::PerOp,
+        snapshot_interval,
+    }
+}
+
+// a registry background with `secret(0)` as its key, keeping its log in `path`
+pub fn start_in(path: &Path, snapshot_interval: u32) -> Requester {
+    let key: &'static SecretKey = Box::leak(Box::new(secret(0)));
+    super::start(key, config(path, snapshot_interval))
+}
@@ -1,208 +1,753 @@
 use anyhow::Context;
 use byteorder::{ByteOrder, LE};
-use cassis::Operation;
+use cassis::{log::Entry, Operation};
 use secp256k1::hashes::{sha256, Hash};
-use std::{ops::RangeBounds, path::Path};
 
+use super::file::AppendFile;
+use std::{
+    fs,
+    ops::{Bound, RangeBounds},
+    path::Path,
+    str::FromStr,
+};
+
+// every record in the hash file is the hash of the entry followed by the chain hash up to it
+const HASH_RECORD_SIZE: usize = 64;
+
+// how long an append waits for the disk
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Durability {
+    // every append is synced before it returns
+    PerOp,
+    // appends are synced all together on `commit`
+    Group,
+    // nothing is ever synced, a crash can lose operations that were already acknowledged
+    None,
+}
+
+impl FromStr for Durability {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "per-op" => Ok(Durability::PerOp),
+            "group" => Ok(Durability::Group),
+            "none" => Ok(Durability::None),
+            _ => Err(anyhow::anyhow!(
+                "durability must be one of per-op, group or none, not {}",
+                s
+            )),
+        }
+    }
+}
+
+// an entry is appended to the log file, then its hashes to the hash file and only after both are
+// on disk its offset is written: the offset file is the commit marker, an entry without an offset
+// was never acknowledged and is dropped by `check_and_heal`.
 pub struct LogStore {
-    offset_mmap: mmap_simple::Mmap,
-    log_mmap: mmap_simple::Mmap,
-    hash_mmap: mmap_simple::Mmap,
+    offset_file: AppendFile,
+    log_file: AppendFile,
+    hash_file: AppendFile,
+    durability: Durability,
+    // offsets of the entries appended since the last commit
+    pending_offsets: Vec<u8>,
 }
 
 impl LogStore {
-    pub fn init(path: &Path) -> Result<Self, anyhow::Error> {
-        Ok(LogStore {
-            offset_mmap: mmap_simple::Mmap::new(&path.join("offset"))
-                .context("failed to mmap offset file")?,
-            log_mmap: mmap_simple::Mmap::new(&path.join("log"))
-                .context("failed to mmap log file")?,
-            hash_mmap: mmap_simple::Mmap::new(&path.join("hash"))
-                .context("failed to mmap hash file")?,
-        })
+    pub fn init(path: &Path, durability: Durability) -> Result<Self, anyhow::Error> {
+        fs::create_dir_all(path).context("failed to create logstore directory")?;
+        let ls = LogStore {
+            offset_file: AppendFile::open(&path.join("offset"))
+                .context("failed to open offset file")?,
+            log_file: AppendFile::open(&path.join("log")).context("failed to open log file")?,
+            hash_file: AppendFile::open(&path.join("hash")).context("failed to open hash file")?,
+            durability,
+            pending_offsets: Vec::new(),
+        };
+
+        // the files may have just been created, so make sure they are still there after a crash
+        fs::File::open(path)
+            .and_then(|dir| dir.sync_all())
+            .context("failed to sync logstore directory")?;
+
+        Ok(ls)
     }
 
+    // brings the three files back to a state where they agree with each other after a crash in the
+    // middle of an append. we keep every entry whose offset was written and whose log bytes are all
+    // there and decode, drop whatever comes after it and recompute the hashes that are missing or
+    // don't match, as the hash file is derived from the log anyway.
     pub fn check_and_heal(&mut self) -> Result<(), anyhow::Error> {
-        // check how many offsets we have written
-        let mut offsetlen = self.offset_mmap.size as usize;
-
-        // this is the size of the log file -- we'll see if this is correct
-        let mut loglen;
-
-        // now check if we have access to the latest log we should according to the offsets file
-        let mut already_read_at_least_one_size = false;
-        loop {
-            // if we had dangling bytes written, ignore them
-            if offsetlen % 4 != 0 {
-                offsetlen -= 1;
-            }
+        // if we had dangling bytes written to the offset file, ignore them
+        let mut count = self.offset_file.size as usize / 4;
 
-            let read_last_op: Result<(), anyhow::Error> = {
-                let offset = LE::read_u32(
-                    self.offset_mmap
-                        .read((offsetlen as usize / 4 - 1) * 4, 4)
-                        .context("failed to read index of last log")?
-                        .as_slice(),
-                ) as usize;
-                let op_size = LE::read_u16(
-                    self.log_mmap
-                        .read(offset, 2)
-                        .inspect_err(|err| {
-                            if already_read_at_least_one_size {
-                                // if we have already read one size further on in this file then this shouldn't have failed at all
-                                // are we going crazy?
-                                panic!("shouldn't have failed to read a part of the file before another part that had already succeeded: {}", err);
-                            }
-                        })
-                        .context("failed to read size of last log")?
-                        .as_slice(),
-                );
+        // find the last entry that can be read entirely, anything after it is broken
+        let mut loglen = 0;
+        while count > 0 {
+            match self.check_entry(count as u32 - 1) {
+                Ok(next_offset) => {
+                    loglen = next_offset as usize;
+                    break;
+                }
+                Err(err) => tracing::warn!("log entry {} not ok: {}; healing", count - 1, err),
+            }
+            count -= 1;
+        }
 
-                already_read_at_least_one_size = true;
+        // truncate the offset and log files to the points in which they are good
+        self.offset_file
+            .drop_from_tail(self.offset_file.size as usize - count * 4)
+            .context("failed to truncate offset file")?;
+        self.log_file
+            .drop_from_tail(self.log_file.size as usize - loglen)
+            .context("failed to truncate log file")?;
 
-                // optimistically set the correct log file size to the current offset + size
-                // if this fails later we will overwrite this variable anyway until it doesn't fail
-                loglen = offset + 2 + (op_size as usize);
+        // the hash records we have that still match the log we have kept
+        let mut hashed = (self.hash_file.size as usize / HASH_RECORD_SIZE).min(count);
+        while hashed > 0 {
+            let previous = match hashed {
+                1 => cassis::log::GENESIS,
+                _ => self.read_hash_record(hashed as u32 - 2)?.1,
+            };
+            if self.read_hash_record(hashed as u32 - 1)?
+                == self.compute_hash_record(hashed as u32 - 1, &previous)?
+            {
+                break;
+            }
+            tracing::warn!("hash record {} doesn't match the log; healing", hashed - 1);
+            hashed -= 1;
+        }
 
-                self.log_mmap
-                    .read_with(offset + 2, op_size as usize, |_| ())
-                    .context("failed to read last log operation")?;
+        self.hash_file
+            .drop_from_tail(self.hash_file.size as usize - hashed * HASH_RECORD_SIZE)
+            .context("failed to truncate hash file")?;
 
-                Ok(())
+        // and recompute the ones that are missing
+        if hashed < count {
+            tracing::warn!("rebuilding hash records {} to {}", hashed, count - 1);
+        }
+        for idx in hashed..count {
+            let previous = match idx {
+                0 => cassis::log::GENESIS,
+                _ => self.read_hash_record(idx as u32 - 1)?.1,
             };
+            let (entry_hash, chain_hash) = self.compute_hash_record(idx as u32, &previous)?;
+            let mut record = [0u8; HASH_RECORD_SIZE];
+            record[0..32].copy_from_slice(&entry_hash);
+            record[32..64].copy_from_slice(&chain_hash);
+            self.hash_file
+                .append(&record)
+                .context("failed to append to hash file")?;
+        }
 
-            match read_last_op {
-                Err(err) => {
-                    tracing::warn!("log file not ok: {}; healing", err);
+        Ok(())
+    }
 
-                    // last log line is broken, so let's try the previous
-                    offsetlen -= 4;
-                }
-                Ok(()) => {
-                    // truncate files to the points in which they are good
-                    let _ = self
-                        .offset_mmap
-                        .drop_from_tail(self.offset_mmap.size as usize - offsetlen)
-                        .context("drop_from_tail 1 failed")?;
-                    let _ = self
-                        .log_mmap
-                        .drop_from_tail(self.offset_mmap.size as usize - loglen)
-                        .context("drop_from_tail 2 failed")?;
+    // an entry is good when it starts where the previous one ends and can be read and decoded in
+    // full, returns the offset where the next entry should start
+    fn check_entry(&self, idx: u32) -> Result<u32, anyhow::Error> {
+        let offset = self.get_offset_for_idx(idx)?;
+        let expected = match idx {
+            0 => 0,
+            _ => {
+                self.read_entry_at_offset(self.get_offset_for_idx(idx - 1)?)?
+                    .1
+            }
+        };
+        if offset != expected {
+            return Err(anyhow::anyhow!(
+                "offset is {}, expected {}",
+                offset,
+                expected
+            ));
+        }
 
-                    break;
-                }
+        let (entry, next_offset) = self.read_entry_at_offset(offset)?;
+        Operation::try_from(entry.as_slice())?;
+        Ok(next_offset)
+    }
+
+    // (entry hash, chain hash) as stored in the hash file
+    fn read_hash_record(&self, idx: u32) -> Result<([u8; 32], [u8; 32]), anyhow::Error> {
+        let record = self
+            .hash_file
+            .read(idx as usize * HASH_RECORD_SIZE, HASH_RECORD_SIZE)
+            .with_context(|| format!("failed to read hash record {}", idx))?;
+        Ok((
+            record[0..32].try_into().unwrap(),
+            record[32..64].try_into().unwrap(),
+        ))
+    }
+
+    // (entry hash, chain hash) as they should be according to the log
+    fn compute_hash_record(
+        &self,
+        idx: u32,
+        previous: &[u8; 32],
+    ) -> Result<([u8; 32], [u8; 32]), anyhow::Error> {
+        let (entry, _) = self.read_entry_at_offset(self.get_offset_for_idx(idx)?)?;
+        let entry_hash = sha256::Hash::hash(&entry).to_byte_array();
+        Ok((entry_hash, cassis::log::chain_hash(previous, &entry_hash)))
+    }
+
+    // operations are only visible, and only survive a crash, after they are committed. with
+    // `Durability::Group` that is up to the caller, otherwise it happens here.
+    pub fn append_operation(&mut self, op: &Operation) -> Result<(), anyhow::Error> {
+        self.append_operations(std::slice::from_ref(op))
+    }
+
+    // appends all operations one after the other or, if any of them fails, none of them. when it
+    // commits too and that fails, none of them are kept either.
+    pub fn append_operations(&mut self, ops: &[Operation]) -> Result<(), anyhow::Error> {
+        let tail = self.tail();
+        for op in ops {
+            let offset = self.log_file.size as u32;
+            if let Err(err) = self.write_entry(op) {
+                // leave the files as they were so the next append doesn't start from a broken entry
+                self.truncate_to(&tail)?;
+                return Err(err);
             }
+
+            self.pending_offsets
+                .extend_from_slice(&offset.to_le_bytes());
         }
 
-        // check hashes file size
-        let hashlen = self.hash_mmap.size as usize;
-        if hashlen != (offsetlen / 4) * 32 {
-            panic!("fix this later");
+        match self.durability {
+            Durability::Group => Ok(()),
+            // the caller won't apply these, so a later commit mustn't make them part of the log
+            Durability::PerOp | Durability::None => self.commit().or_else(|err| {
+                self.truncate_to(&tail)?;
+                Err(err)
+            }),
         }
+    }
 
+    fn tail(&self) -> Tail {
+        Tail {
+            offsets: self.offset_file.size,
+            log: self.log_file.size,
+            hashes: self.hash_file.size,
+            pending: self.pending_offsets.len(),
+        }
+    }
+
+    // drops everything written after `tail` was taken, committed or not
+    fn truncate_to(&mut self, tail: &Tail) -> Result<(), anyhow::Error> {
+        self.offset_file
+            .drop_from_tail((self.offset_file.size - tail.offsets) as usize)
+            .context("failed to roll back offset file")?;
+        self.log_file
+            .drop_from_tail((self.log_file.size - tail.log) as usize)
+            .context("failed to roll back log file")?;
+        self.hash_file
+            .drop_from_tail((self.hash_file.size - tail.hashes) as usize)
+            .context("failed to roll back hash file")?;
+        self.pending_offsets.truncate(tail.pending);
         Ok(())
     }
 
-    pub fn append_operation(&mut self, op: &Operation) -> Result<(), anyhow::Error> {
-        self.offset_mmap.append_with(4, |w| {
-            LE::write_u32(w, self.log_mmap.size as u32);
-        })?;
-
-        self.log_mmap.append_with(2 + op.size() as usize, |w| {
-            LE::write_u16(w, op.size() as u16);
-            op.write_serialized(&mut w[2..]);
-
-            let x = sha256::Hash::hash(&w[2..]);
-            let hash = x.as_byte_array();
-            match self.hash_mmap.append(hash).context("append failed") {
-                Err(x) => panic!("{}", x),
-                Ok(_) => {}
-            }
-        })?;
+    fn write_entry(&mut self, op: &Operation) -> Result<(), anyhow::Error> {
+        // the chain continues from the last entry written, committed or not
+        let previous = match self.hash_file.size as usize / HASH_RECORD_SIZE {
+            0 => cassis::log::GENESIS,
+            count => self.read_hash_record(count as u32 - 1)?.1,
+        };
+
+        let mut entry = vec![0u8; 2 + op.size()];
+        LE::write_u16(&mut entry, op.size() as u16);
+        op.write_serialized(&mut entry[2..]);
+        self.log_file
+            .append(&entry)
+            .context("failed to append to log file")?;
+
+        let entry_hash = sha256::Hash::hash(&entry[2..]).to_byte_array();
+        let mut record = [0u8; HASH_RECORD_SIZE];
+        record[0..32].copy_from_slice(&entry_hash);
+        record[32..64].copy_from_slice(&cassis::log::chain_hash(&previous, &entry_hash));
+        self.hash_file
+            .append(&record)
+            .context("failed to append to hash file")?;
+
         Ok(())
     }
 
+    // makes everything appended so far durable (unless durability is `None`) and then visible
+    pub fn commit(&mut self) -> Result<(), anyhow::Error> {
+        if self.pending_offsets.is_empty() {
+            return Ok(());
+        }
+
+        let sync = self.durability != Durability::None;
+        if sync {
+            self.log_file.sync().context("failed to sync log file")?;
+            self.hash_file.sync().context("failed to sync hash file")?;
+        }
+
+        // the offsets are what makes the entries committed, so none of them can be left behind if
+        // writing them fails, or the next `check_and_heal` would keep entries that were rejected
+        let committed = self.offset_file.size;
+        if let Err(err) = self.write_offsets(sync) {
+            self.offset_file
+                .truncate(committed)
+                .context("failed to roll back offset file")?;
+            return Err(err);
+        }
+
+        self.pending_offsets.clear();
+        Ok(())
+    }
+
+    fn write_offsets(&mut self, sync: bool) -> Result<(), anyhow::Error> {
+        self.offset_file
+            .append(&self.pending_offsets)
+            .context("failed to append to offset file")?;
+        if sync {
+            self.offset_file
+                .sync()
+                .context("failed to sync offset file")?;
+        }
+        Ok(())
+    }
+
+    // every commit from now on fails once its offsets are written
+    #[cfg(test)]
+    pub fn fail_offset_syncs(&mut self) {
+        self.offset_file.fail_syncs = true;
+    }
+
+    // only what was committed
+    pub fn len(&self) -> u32 {
+        (self.offset_file.size / 4) as u32
+    }
+
+    // the index the next appended entry will get, counting those not committed yet
+    pub fn next_idx(&self) -> u32 {
+        self.len() + (self.pending_offsets.len() / 4) as u32
+    }
+
+    pub fn chain_hash(&self, idx: u32) -> Result<[u8; 32], anyhow::Error> {
+        if idx >= self.len() {
+            return Err(anyhow::anyhow!("no entry {}", idx));
+        }
+        Ok(self.read_hash_record(idx)?.1)
+    }
+
+    // index and chain hash of the last entry, if there is any
+    pub fn head(&self) -> Option<(u32, [u8; 32])> {
+        let idx = self.len().checked_sub(1)?;
+        let record = self
+            .hash_file
+            .read(idx as usize * HASH_RECORD_SIZE, HASH_RECORD_SIZE)
+            .ok()?;
+        Some((idx, record[32..64].try_into().unwrap()))
+    }
+
+    // the hash of every entry, in order
+    pub fn entry_hashes(&self) -> Result<Vec<[u8; 32]>, anyhow::Error> {
+        let records = self
+            .hash_file
+            .read(0, self.len() as usize * HASH_RECORD_SIZE)
+            .context("failed to read hash file")?;
+        Ok(records
+            .chunks_exact(HASH_RECORD_SIZE)
+            .map(|record| record[0..32].try_into().unwrap())
+            .collect())
+    }
+
     pub fn read_operation(&self, idx: u32) -> Result<Operation, anyhow::Error> {
         self.read_operation_at_offset(self.get_offset_for_idx(idx)?)
             .map(|(op, _)| op)
     }
 
+    // the operation at `idx` with its chain hash, only once it is committed
+    pub fn read_entry(&self, idx: u32) -> Result<Entry, anyhow::Error> {
+        Ok(Entry {
+            idx,
+            hash: self.chain_hash(idx)?,
+            op: self.read_operation(idx)?,
+        })
+    }
+
     fn get_offset_for_idx(&self, idx: u32) -> Result<u32, anyhow::Error> {
-        let mut offset = 0u32;
-        self.offset_mmap
-            .read_with(idx as usize * 4, 4, |r| {
-                offset = LE::read_u32(r);
-            })
-            .with_context(|| format!("failed to read offset_file at {}", idx * 2))?;
-        Ok(offset)
+        let offset = self
+            .offset_file
+            .read(idx as usize * 4, 4)
+            .with_context(|| format!("failed to read offset_file at {}", idx * 4))?;
+        Ok(LE::read_u32(&offset))
     }
 
-    fn read_operation_at_offset(&self, offset: u32) -> Result<(Operation, u32), anyhow::Error> {
-        let mut size = 0u16;
-        self.log_mmap
-            .read_with(offset as usize, 2, |r| {
-                size = LE::read_u16(r);
-            })
-            .with_context(|| format!("failed to read log_file at {}", offset))?;
-
-        let mut op: Option<Operation> = None;
-        self.log_mmap
-            .read_with(offset as usize + 2, size as usize, |r| {
-                op = Some(Operation::deserialize(r));
-            })
+    // the raw bytes of the entry and the offset of the next one
+    fn read_entry_at_offset(&self, offset: u32) -> Result<(Vec<u8>, u32), anyhow::Error> {
+        let size = LE::read_u16(
+            &self
+                .log_file
+                .read(offset as usize, 2)
+                .with_context(|| format!("failed to read log_file at {}", offset))?,
+        );
+
+        let entry = self
+            .log_file
+            .read(offset as usize + 2, size as usize)
             .with_context(|| format!("failed to read log_file at {}", offset + 2))?;
 
-        Ok((op.unwrap(), offset + 2 + size as u32))
+        Ok((entry, offset + 2 + size as u32))
+    }
+
+    fn read_operation_at_offset(&self, offset: u32) -> Result<(Operation, u32), anyhow::Error> {
+        let (entry, next_offset) = self.read_entry_at_offset(offset)?;
+        let op = Operation::try_from(entry.as_slice())
+            .with_context(|| format!("invalid operation in log_file at {}", offset + 2))?;
+        Ok((op, next_offset))
     }
 
     pub fn iter(&self) -> LogStoreIter<'_> {
         LogStoreIter {
             store: self,
             offset: 0,
-            offset_end: None,
+            remaining: self.len(),
         }
     }
 
+    // only committed entries, bounds past the last one are clamped to it
     pub fn range(&self, range: impl RangeBounds<u32>) -> Result<LogStoreIter<'_>, anyhow::Error> {
-        let offset_start = match range.start_bound() {
-            std::ops::Bound::Unbounded => 0,
-            std::ops::Bound::Included(idx) => self.get_offset_for_idx(*idx)?,
-            std::ops::Bound::Excluded(idx) => self.get_offset_for_idx(idx + 1)?,
-        };
-        let offset_end = match range.end_bound() {
-            std::ops::Bound::Unbounded => None,
-            std::ops::Bound::Included(idx) => Some(self.get_offset_for_idx(*idx)?),
-            std::ops::Bound::Excluded(idx) => Some(self.get_offset_for_idx(idx + 1)?),
+        let end = match range.end_bound() {
+            Bound::Unbounded => self.len(),
+            Bound::Included(idx) => idx.saturating_add(1).min(self.len()),
+            Bound::Excluded(idx) => (*idx).min(self.len()),
         };
+        let start = match range.start_bound() {
+            Bound::Unbounded => 0,
+            Bound::Included(idx) => *idx,
+            Bound::Excluded(idx) => idx.saturating_add(1),
+        }
+        .min(end);
 
         Ok(LogStoreIter {
             store: self,
-            offset: offset_start,
-            offset_end,
+            offset: match start == end {
+                true => 0,
+                false => self.get_offset_for_idx(start)?,
+            },
+            remaining: end - start,
         })
     }
 }
 
+// how long each file and the pending offsets were at some point
+struct Tail {
+    offsets: u64,
+    log: u64,
+    hashes: u64,
+    pending: usize,
+}
+
+// a committed entry that can't be read is an error, after which nothing else is given
 pub(crate) struct LogStoreIter<'a> {
     store: &'a LogStore,
     offset: u32,
-    offset_end: Option<u32>,
+    remaining: u32,
 }
 
 impl Iterator for LogStoreIter<'_> {
-    type Item = Operation;
+    type Item = Result<Operation, anyhow::Error>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        if Some(self.offset) == self.offset_end {
+        if self.remaining == 0 {
             return None;
         }
 
         match self.store.read_operation_at_offset(self.offset) {
             Ok((op, next_offset)) => {
                 self.offset = next_offset;
-                Some(op)
+                self.remaining -= 1;
+                Some(Ok(op))
+            }
+            Err(err) => {
+                self.remaining = 0;
+                Some(Err(err))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::background::common::open_store;
+    use cassis::{
+        log::{chain, entry_hash, GENESIS},
+        test_utils::{hop, trust},
+        PeerSig, Transfer,
+    };
+
+    const FILES: [&str; 3] = ["offset", "log", "hash"];
+
+    // the store doesn't validate anything, so these just have to be of different sizes
+    fn operations() -> Vec<Operation> {
+        vec![
+            trust(0, 1, 10),
+            Operation::Transfer(Transfer {
+                ts: 1,
+                hops: vec![hop(1, 0, 5)],
+                sigs: vec![PeerSig {
+                    peer_idx: 1,
+                    sig: [7; 64],
+                }],
+            }),
+            trust(0, 2, 20),
+        ]
+    }
+
+    fn entry_len(op: &Operation) -> usize {
+        2 + op.size()
+    }
+
+    // the store holds exactly the first `count` operations, its files agree with each other and
+    // it can still be appended to
+    fn assert_consistent(path: &Path, ops: &[Operation], count: usize) {
+        let mut ls = open_store(path, Durability::PerOp);
+
+        assert_eq!(ls.len() as usize, count);
+        assert_eq!(
+            ls.iter().collect::<Result<Vec<_>, _>>().unwrap(),
+            ops[..count]
+        );
+        assert_eq!(
+            ls.entry_hashes().unwrap(),
+            ops[..count].iter().map(entry_hash).collect::<Vec<_>>()
+        );
+        assert_eq!(
+            ls.head(),
+            count
+                .checked_sub(1)
+                .map(|idx| (idx as u32, chain(GENESIS, &ops[..count])))
+        );
+
+        let file_len = |name: &str| fs::metadata(path.join(name)).unwrap().len() as usize;
+        assert_eq!(file_len("offset"), count * 4);
+        assert_eq!(
+            file_len("log"),
+            ops[..count].iter().map(entry_len).sum::<usize>()
+        );
+        assert_eq!(file_len("hash"), count * HASH_RECORD_SIZE);
+
+        let next = &ops[count % ops.len()];
+        ls.append_operation(next).unwrap();
+        drop(ls);
+        let ls = open_store(path, Durability::PerOp);
+        assert_eq!(ls.len() as usize, count + 1);
+        assert_eq!(ls.read_operation(count as u32).unwrap(), *next);
+    }
+
+    fn write_store(path: &Path, ops: &[Operation]) -> Vec<Vec<u8>> {
+        let mut ls = open_store(path, Durability::PerOp);
+        for op in ops {
+            ls.append_operation(op).unwrap();
+        }
+        FILES
+            .iter()
+            .map(|name| fs::read(path.join(name)).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn range_has_the_bounds_it_was_given() {
+        let ops: Vec<Operation> = operations().into_iter().cycle().take(5).collect();
+        let dir = tempfile::tempdir().unwrap();
+        let mut ls = open_store(dir.path(), Durability::Group);
+        assert_eq!(ls.range(..).unwrap().count(), 0);
+        assert_eq!(ls.range(3..).unwrap().count(), 0);
+
+        for op in ops.iter() {
+            ls.append_operation(op).unwrap();
+        }
+        ls.commit().unwrap();
+        // not committed, so never part of a range
+        ls.append_operation(&ops[0]).unwrap();
+
+        let starts = [
+            Bound::Unbounded,
+            Bound::Included(0),
+            Bound::Included(2),
+            Bound::Included(5),
+            Bound::Included(7),
+            Bound::Excluded(0),
+            Bound::Excluded(2),
+            Bound::Excluded(4),
+            Bound::Excluded(u32::MAX),
+        ];
+        let ends = [
+            Bound::Unbounded,
+            Bound::Included(0),
+            Bound::Included(2),
+            Bound::Included(4),
+            Bound::Included(u32::MAX),
+            Bound::Excluded(0),
+            Bound::Excluded(2),
+            Bound::Excluded(5),
+            Bound::Excluded(7),
+        ];
+        for start in starts {
+            for end in ends {
+                let expected: Vec<Operation> = (0..ops.len() as u32)
+                    .filter(|idx| (start, end).contains(idx))
+                    .map(|idx| ops[idx as usize].clone())
+                    .collect();
+                assert_eq!(
+                    ls.range((start, end))
+                        .unwrap()
+                        .collect::<Result<Vec<_>, _>>()
+                        .unwrap(),
+                    expected,
+                    "{:?} to {:?}",
+                    start,
+                    end
+                );
+            }
+        }
+        assert_eq!(ls.iter().collect::<Result<Vec<_>, _>>().unwrap(), ops);
+    }
+
+    #[test]
+    fn group_appends_are_only_kept_once_committed() {
+        let ops = operations();
+        let dir = tempfile::tempdir().unwrap();
+
+        let mut ls = open_store(dir.path(), Durability::Group);
+        ls.append_operation(&ops[0]).unwrap();
+        ls.commit().unwrap();
+        ls.append_operation(&ops[1]).unwrap();
+        ls.append_operation(&ops[2]).unwrap();
+        assert_eq!(ls.len(), 1);
+        assert_eq!(ls.next_idx(), 3);
+
+        // crash before the second commit
+        drop(ls);
+        assert_consistent(dir.path(), &ops, 1);
+
+        let mut ls = open_store(dir.path(), Durability::Group);
+        for op in ops[2..].iter() {
+            ls.append_operation(op).unwrap();
+        }
+        ls.commit().unwrap();
+        assert_eq!(ls.len(), 3);
+        assert_eq!(ls.read_operation(2).unwrap(), ops[2]);
+        assert_eq!(
+            ls.head(),
+            Some((2, chain(GENESIS, [&ops[0], &ops[1], &ops[2]])))
+        );
+    }
+
+    #[test]
+    fn appends_that_fail_to_commit_are_dropped() {
+        let ops = operations();
+        let dir = tempfile::tempdir().unwrap();
+
+        // as if the commit at the end of the second append had failed after writing its offset
+        let mut ls = open_store(dir.path(), Durability::PerOp);
+        ls.append_operation(&ops[0]).unwrap();
+        let tail = ls.tail();
+        ls.append_operation(&ops[1]).unwrap();
+        ls.truncate_to(&tail).unwrap();
+        assert_eq!((ls.len(), ls.next_idx()), (1, 1));
+
+        // so what comes next takes its place, and nothing of it is left after a restart
+        ls.append_operation(&ops[2]).unwrap();
+        assert_eq!(ls.read_operation(1).unwrap(), ops[2]);
+        drop(ls);
+        assert_consistent(dir.path(), &[ops[0].clone(), ops[2].clone()], 2);
+    }
+
+    #[test]
+    fn offsets_of_a_failed_group_commit_are_not_kept() {
+        let ops = operations();
+        let dir = tempfile::tempdir().unwrap();
+
+        let mut ls = open_store(dir.path(), Durability::Group);
+        ls.append_operation(&ops[0]).unwrap();
+        ls.commit().unwrap();
+        ls.append_operation(&ops[1]).unwrap();
+        ls.append_operation(&ops[2]).unwrap();
+        ls.fail_offset_syncs();
+        assert!(ls.commit().is_err());
+        assert_eq!(ls.len(), 1);
+
+        // they were written but never made it, so they are gone after a restart
+        drop(ls);
+        assert_consistent(dir.path(), &ops, 1);
+    }
+
+    #[test]
+    fn fresh_directory_is_an_empty_store() {
+        let dir = tempfile::tempdir().unwrap();
+        assert_consistent(&dir.path().join("new").join("store"), &operations(), 0);
+    }
+
+    #[test]
+    fn reopens_consistent_after_torn_writes() {
+        let ops = operations();
+        let original = tempfile::tempdir().unwrap();
+        let contents = write_store(original.path(), &ops);
+
+        for (f, name) in FILES.iter().enumerate() {
+            for cut in 0..contents[f].len() {
+                let dir = tempfile::tempdir().unwrap();
+                for (g, other) in FILES.iter().enumerate() {
+                    let data = if g == f {
+                        &contents[g][..cut]
+                    } else {
+                        &contents[g][..]
+                    };
+                    fs::write(dir.path().join(other), data).unwrap();
+                }
+
+                // whatever is left in the log is all we can keep, the hashes can be rebuilt
+                let expected = match *name {
+                    "offset" => cut / 4,
+                    "log" => (0..=ops.len())
+                        .take_while(|n| ops[..*n].iter().map(entry_len).sum::<usize>() <= cut)
+                        .last()
+                        .unwrap(),
+                    _ => ops.len(),
+                };
+                assert_consistent(dir.path(), &ops, expected);
+            }
+        }
+    }
+
+    #[test]
+    fn reopens_consistent_after_unwritten_pages() {
+        // the file was extended but what was written to it never reached the disk
+        let ops = operations();
+        let original = tempfile::tempdir().unwrap();
+        let contents = write_store(original.path(), &ops);
+
+        for (f, name) in FILES.iter().enumerate() {
+            // zeros in the log could still decode as an operation, so that one relies on the size
+            // and on the offsets
+            if *name == "log" {
+                continue;
+            }
+
+            for from in 0..contents[f].len() {
+                let mut zeroed = contents[f].clone();
+                zeroed[from..].fill(0);
+
+                let dir = tempfile::tempdir().unwrap();
+                for (g, other) in FILES.iter().enumerate() {
+                    let data = if g == f { &zeroed } else { &contents[g] };
+                    fs::write(dir.path().join(other), data).unwrap();
+                }
+
+                // offsets that were already zero where they got zeroed are still right
+                let expected = match *name {
+                    "offset" => (0..ops.len())
+                        .take_while(|i| zeroed[i * 4..i * 4 + 4] == contents[f][i * 4..i * 4 + 4])
+                        .count(),
+                    _ => ops.len(),
+                };
+                assert_consistent(dir.path(), &ops, expected);
             }
-            Err(_) => None,
         }
     }
 }
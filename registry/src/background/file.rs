@@ -0,0 +1,70 @@
+use std::{fs, io, os::unix::fs::FileExt, path::Path};
+
+// a file that only grows at the end, can be read anywhere and shrunk from the end. appends only
+// reach the disk for sure after `sync`.
+pub struct AppendFile {
+    file: fs::File,
+    pub size: u64,
+    // makes every `sync` fail, to see what happens when the disk does
+    #[cfg(test)]
+    pub fail_syncs: bool,
+}
+
+impl AppendFile {
+    pub fn open(path: &Path) -> Result<Self, io::Error> {
+        let file = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .truncate(false)
+            .create(true)
+            .open(path)?;
+        let size = file.metadata()?.len();
+        Ok(AppendFile {
+            file,
+            size,
+            #[cfg(test)]
+            fail_syncs: false,
+        })
+    }
+
+    pub fn append(&mut self, data: &[u8]) -> Result<(), io::Error> {
+        self.file.write_all_at(data, self.size)?;
+        self.size += data.len() as u64;
+        Ok(())
+    }
+
+    pub fn sync(&self) -> Result<(), io::Error> {
+        #[cfg(test)]
+        if self.fail_syncs {
+            return Err(io::Error::other("sync failed on purpose"));
+        }
+        self.file.sync_data()
+    }
+
+    pub fn drop_from_tail(&mut self, len: usize) -> Result<(), io::Error> {
+        // healing a clean log drops nothing, and shouldn't cost a sync
+        if len == 0 {
+            return Ok(());
+        }
+        self.file.set_len(self.size - len as u64)?;
+        self.size -= len as u64;
+        self.file.sync_data()
+    }
+
+    // back to `size`, also dropping whatever a failed append may have left after it
+    pub fn truncate(&mut self, size: u64) -> Result<(), io::Error> {
+        self.file.set_len(size)?;
+        self.size = size;
+        self.file.sync_data()
+    }
+
+    pub fn read(&self, offset: usize, len: usize) -> Result<Vec<u8>, io::Error> {
+        if offset + len > self.size as usize {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+        }
+
+        let mut buf = vec![0u8; len];
+        self.file.read_exact_at(&mut buf, offset as u64)?;
+        Ok(buf)
+    }
+}
@@ -0,0 +1,150 @@
+use anyhow::anyhow;
+use byteorder::{ByteOrder, LE};
+use cassis::{Operation, State};
+
+// the log indexes of the operations that touch each key, in the order they were appended. it is
+// saved in snapshots together with the state and, like it, only the log after the snapshot is
+// replayed on start.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct History {
+    entries: Vec<Vec<u32>>,
+}
+
+impl History {
+    // records the operation at `idx` for all keys it touches: the two sides of a trust, and the
+    // ends of every hop and every signer of a transfer
+    pub fn add(&mut self, state: &State, idx: u32, op: &Operation) {
+        let mut keys = match op {
+            Operation::Unknown => vec![],
+            Operation::Trust(t) => vec![t.from, state.key_indexes[&t.to.serialize()]],
+            Operation::Transfer(t) => t
+                .hops
+                .iter()
+                .flat_map(|hop| [hop.from, hop.to])
+                .chain(t.sigs.iter().map(|sig| sig.peer_idx))
+                .collect(),
+        };
+        keys.sort_unstable();
+        keys.dedup();
+
+        for key in keys {
+            if self.entries.len() <= key as usize {
+                self.entries.resize(key as usize + 1, Vec::new());
+            }
+            self.entries[key as usize].push(idx);
+        }
+    }
+
+    // up to `limit` log indexes for this key that come after `after`, and whether there are more
+    pub fn page(&self, key: u32, after: Option<u32>, limit: usize) -> (&[u32], bool) {
+        let entries = self
+            .entries
+            .get(key as usize)
+            .map_or(&[][..], Vec::as_slice);
+        let start = match after {
+            None => 0,
+            Some(after) => entries.partition_point(|idx| *idx <= after),
+        };
+        let end = entries.len().min(start + limit);
+        (&entries[start..end], end < entries.len())
+    }
+
+    // [nkeys u32][for each key: [n u32][n indexes u32]], little-endian
+    pub fn write_to(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&(self.entries.len() as u32).to_le_bytes());
+        for entries in self.entries.iter() {
+            buf.extend_from_slice(&(entries.len() as u32).to_le_bytes());
+            for idx in entries {
+                buf.extend_from_slice(&idx.to_le_bytes());
+            }
+        }
+    }
+
+    // reads what `write_to` wrote from the start of `buf`, leaving the rest in it
+    pub fn read_from(buf: &mut &[u8]) -> Result<Self, anyhow::Error> {
+        fn u32(buf: &mut &[u8]) -> Result<u32, anyhow::Error> {
+            if buf.len() < 4 {
+                return Err(anyhow!("history ended too early"));
+            }
+            let (value, rest) = buf.split_at(4);
+            *buf = rest;
+            Ok(LE::read_u32(value))
+        }
+
+        // every count is followed by at least that many u32s, so none can be more than a quarter of
+        // what is left
+        let nkeys = u32(buf)? as usize;
+        let mut entries = Vec::with_capacity(nkeys.min(buf.len() / 4));
+        for key in 0..nkeys {
+            let n = u32(buf)? as usize;
+            let mut indexes = Vec::with_capacity(n.min(buf.len() / 4));
+            for _ in 0..n {
+                let idx = u32(buf)?;
+                if indexes.last().is_some_and(|last| *last >= idx) {
+                    return Err(anyhow!("history of key {} is out of order", key));
+                }
+                indexes.push(idx);
+            }
+            entries.push(indexes);
+        }
+        Ok(History { entries })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::background::{common::open_store, db::Durability};
+    use cassis::test_utils::{empty_state, hop, secret, signed_transfer, trust};
+
+    #[test]
+    fn history_is_paged_and_rebuilt_from_the_log() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut ls = open_store(dir.path(), Durability::PerOp);
+
+        let mut state = empty_state();
+        let mut history = History::default();
+
+        let mut ops = vec![
+            trust(0, 1, 100),
+            trust(0, 2, 50),
+            trust(1, 0, 10),
+            signed_transfer(vec![hop(2, 0, 5), hop(0, 1, 5)]),
+        ];
+        ops.extend((0..10).map(|i| trust(2, 3, i + 1)));
+        for op in ops.iter() {
+            cassis::state::validate(&state, op).unwrap();
+            cassis::state::process(&mut state, op);
+            ls.append_operation(op).unwrap();
+            history.add(&state, ls.len() - 1, op);
+        }
+
+        assert_eq!(history.page(0, None, 50), (&[0, 1, 2, 3][..], false));
+        assert_eq!(history.page(1, None, 50), (&[0, 2, 3][..], false));
+        assert_eq!(history.page(2, None, 3), (&[1, 3, 4][..], true));
+        assert_eq!(history.page(2, Some(4), 3), (&[5, 6, 7][..], true));
+        assert_eq!(history.page(2, Some(10), 3), (&[11, 12, 13][..], false));
+        assert_eq!(history.page(2, Some(13), 3), (&[][..], false));
+        assert_eq!(history.page(9, None, 3), (&[][..], false));
+
+        let (_, rebuilt, _) =
+            crate::background::state::init(secret(0).public(), &ls, &dir.path().join("none"))
+                .unwrap();
+        assert_eq!(rebuilt, history);
+
+        // and it reads back what it wrote, but nothing shorter
+        let mut buf = Vec::new();
+        history.write_to(&mut buf);
+        buf.push(9);
+        let mut rest = buf.as_slice();
+        assert_eq!(History::read_from(&mut rest).unwrap(), history);
+        assert_eq!(rest, [9]);
+        for len in 0..buf.len() - 1 {
+            assert!(
+                History::read_from(&mut &buf[..len]).is_err(),
+                "cut at {}",
+                len
+            );
+        }
+    }
+}
@@ -1,102 +1,492 @@
-use anyhow::anyhow;
-use std::{env, path::Path, sync::mpsc, thread};
-use tokio::sync::oneshot;
+use anyhow::{anyhow, Context};
+use cassis::state::{StateTree, ValidationError};
+use cassis::{
+    log::{ConsistencyProof, Entry, Head, InclusionProof, LineProof},
+    merkle, State,
+};
+use futures::Stream;
+use std::{env, fmt, path::PathBuf, sync::mpsc, thread};
+use tokio::sync::{broadcast, oneshot};
 
+mod account;
+#[cfg(test)]
+pub(crate) mod common;
 mod db;
+mod file;
+mod history;
+mod snapshot;
 mod state;
 
-use db::LogStore;
+use account::{Account, Adjacency};
+use db::{Durability, LogStore};
+use history::History;
 
-pub fn start(pk: cassis::PublicKey) -> Requester {
+// where the log is kept and how
+#[derive(Debug, Clone)]
+pub struct Config {
+    path: PathBuf,
+    durability: Durability,
+    // how many entries are appended between snapshots
+    snapshot_interval: u32,
+}
+
+impl Config {
+    pub fn from_env() -> Self {
+        Config {
+            path: env::var("STORE_PATH")
+                .unwrap_or("logstore".to_string())
+                .into(),
+            durability: env::var("DURABILITY")
+                .unwrap_or("group".to_string())
+                .parse::<Durability>()
+                .expect("invalid DURABILITY"),
+            snapshot_interval: env::var("SNAPSHOT_INTERVAL")
+                .unwrap_or("10000".to_string())
+                .parse::<u32>()
+                .expect("invalid SNAPSHOT_INTERVAL"),
+        }
+    }
+
+    fn snapshots(&self) -> PathBuf {
+        self.path.join("snapshots")
+    }
+}
+
+pub fn start(secret_key: &'static cassis::SecretKey, config: Config) -> Requester {
     let (tx, rx) = mpsc::channel::<(oneshot::Sender<Response>, Request)>();
+    let (broadcaster, _) = broadcast::channel::<Entry>(BROADCAST_CAPACITY);
+    let mut background =
+        Background::load(secret_key, config, broadcaster.clone()).expect("failed to load the log");
 
     let _join = thread::spawn(move || {
-        let logstore_path = env::var("STORE_PATH").unwrap_or("logstore".to_string());
-        let mut ls =
-            LogStore::init(&Path::new(&logstore_path)).expect("failed to instantiate logstore");
+        while let Ok(first) = rx.recv() {
+            // take everything else that is already waiting too
+            let mut batch = vec![first];
+            while batch.len() < MAX_BATCH {
+                match rx.try_recv() {
+                    Ok(req) => batch.push(req),
+                    Err(_) => break,
+                }
+            }
+
+            for (tx, req) in batch {
+                match req {
+                    Request::AppendOperation(op) => background.append_operation(tx, op),
+                    Request::AppendBatch(ops) => background.append_batch(tx, ops),
+                    // everything else only sees what was committed
+                    req => {
+                        background.commit();
+                        // whoever asked may have gone away already
+                        let _ = tx.send(background.answer(req));
+                    }
+                }
+            }
+
+            background.commit();
+            background.snapshot_if_due();
+        }
+    });
+
+    Requester {
+        sender: tx,
+        broadcaster,
+    }
+}
+
+// everything the background thread keeps, all of which follows from the log on disk
+struct Background {
+    secret_key: &'static cassis::SecretKey,
+    config: Config,
+    broadcaster: broadcast::Sender<Entry>,
+    ls: LogStore,
+    state: State,
+    history: History,
+    // how many entries the latest snapshot has
+    snapshotted: u32,
+    // the one snapshot being written, if any, there is never more than one at a time
+    snapshotting: Option<thread::JoinHandle<()>>,
+    tree: merkle::Tree,
+    state_tree: StateTree,
+    adjacency: Adjacency,
+    // the latest entry signed by us, this only changes when something is committed
+    head: Option<Head>,
+    // appends that were written but not committed yet, waiting to be answered together with the
+    // index of the first entry they appended
+    waiting: Vec<(oneshot::Sender<Response>, u32)>,
+}
+
+impl Background {
+    fn load(
+        secret_key: &'static cassis::SecretKey,
+        config: Config,
+        broadcaster: broadcast::Sender<Entry>,
+    ) -> Result<Self, anyhow::Error> {
+        let mut ls = LogStore::init(&config.path, config.durability)
+            .context("failed to instantiate logstore")?;
         ls.check_and_heal()
-            .expect("failed to check and heal logstore");
+            .context("failed to check and heal logstore")?;
 
-        let mut state = state::init(pk, &ls).expect("failed to initialize state");
+        let (state, history, snapshotted) =
+            state::init(secret_key.public(), &ls, &config.snapshots())
+                .context("failed to initialize state")?;
 
-        for req in rx {
-            let resp = match req.1 {
-                Request::AppendOperation(op) => {
-                    // validate this operation
-                    let _ = match cassis::state::validate(&state, &op) {
-                        Err(err) => return Err(err),
-                        _ => {}
-                    };
+        let mut tree = merkle::Tree::new();
+        for entry_hash in ls.entry_hashes().context("failed to read entry hashes")? {
+            tree.push(&entry_hash);
+        }
 
-                    // once we know it's ok we append it
-                    ls.append_operation(&op)
-                        .map_or_else(|e| Response::Error(e), |_| Response::OK);
+        let mut background = Background {
+            secret_key,
+            config,
+            broadcaster,
+            state_tree: StateTree::new(&state),
+            adjacency: Adjacency::new(&state),
+            ls,
+            state,
+            history,
+            snapshotted,
+            snapshotting: None,
+            tree,
+            head: None,
+            waiting: Vec::with_capacity(MAX_BATCH),
+        };
+        background.sign_head();
+        Ok(background)
+    }
 
-                    // and then we apply the changes
-                    cassis::state::process(&mut state, &op);
+    fn sign_head(&mut self) {
+        self.head = self.ls.head().map(|(idx, hash)| {
+            Head::sign(
+                self.secret_key,
+                idx,
+                hash,
+                self.tree.root(),
+                self.state_tree.root(),
+            )
+        });
+    }
+
+    // the state and everything built from it catch up with operations that were just appended
+    fn applied(&mut self, first: u32, ops: &[cassis::Operation]) {
+        for (i, op) in ops.iter().enumerate() {
+            self.state_tree.update(&self.state, op);
+            self.adjacency.update(&self.state, op);
+            self.history.add(&self.state, first + i as u32, op);
+            self.tree.push(&cassis::log::entry_hash(op));
+        }
+    }
+
+    fn append_operation(&mut self, tx: oneshot::Sender<Response>, op: cassis::Operation) {
+        // validate this operation against the state with everything before it applied
+        if let Err(err) = cassis::state::validate(&self.state, &op) {
+            let _ = tx.send(Response::Error(err.into()));
+            return;
+        }
+
+        // once we know it's ok we append it
+        if let Err(err) = self.ls.append_operation(&op) {
+            let _ = tx.send(Response::Error(err));
+            return;
+        }
+
+        // and then we apply the changes, it is only answered after the commit
+        let idx = self.ls.next_idx() - 1;
+        cassis::state::process(&mut self.state, &op);
+        self.applied(idx, std::slice::from_ref(&op));
+        self.waiting.push((tx, idx));
+    }
+
+    fn append_batch(&mut self, tx: oneshot::Sender<Response>, ops: Vec<cassis::Operation>) {
+        // each operation is validated with the ones before it in the batch applied, but on a copy,
+        // so nothing changes unless all of them are ok
+        let mut scratch = self.state.clone();
+        let checked: Result<(), BatchError> = ops.iter().enumerate().try_for_each(|(index, op)| {
+            cassis::state::validate(&scratch, op).map_err(|error| BatchError { index, error })?;
+            cassis::state::process(&mut scratch, op);
+            Ok(())
+        });
+        if let Err(err) = checked {
+            let _ = tx.send(Response::Error(err.into()));
+            return;
+        }
+
+        if let Err(err) = self.ls.append_operations(&ops) {
+            let _ = tx.send(Response::Error(err));
+            return;
+        }
 
-                    Response::OK
+        self.state = scratch;
+        let first = self.ls.next_idx() - ops.len() as u32;
+        self.applied(first, &ops);
+        self.waiting.push((tx, first));
+    }
+
+    // makes the appends durable with a single sync and only then tells their senders they are ok,
+    // and everyone following the log what was appended
+    fn commit(&mut self) {
+        if self.waiting.is_empty() {
+            return;
+        }
+
+        // with durability other than `Group` the appends were already committed when they were
+        // made, so what is new is everything from the first one still waiting to be answered
+        let from = self.waiting[0].1;
+        if let Err(err) = self.ls.commit() {
+            tracing::error!("failed to commit appends to logstore: {:#}", err);
+            self.fail(err);
+            return;
+        }
+
+        // this happens before anything else is answered, so whoever subscribes and then lists the
+        // log gets every entry from one or the other
+        for idx in from..self.ls.len() {
+            match self.ls.read_entry(idx) {
+                // it is fine for nobody to be listening
+                Ok(entry) => {
+                    let _ = self.broadcaster.send(entry);
                 }
-                Request::ListOperations(from, to) => {
-                    let iter_res = match (from, to) {
-                        (None, None) => ls.range(..50),
-                        (Some(from), None) => ls.range(from..from + 50),
-                        (None, Some(to)) => ls.range(to - 50..to),
-                        (Some(from), Some(to)) => ls.range(from..to),
-                    };
-
-                    iter_res
-                        .map(|range| {
-                            Response::Operations(range.collect::<Vec<cassis::Operation>>())
+                Err(err) => tracing::warn!("failed to read entry {} to broadcast: {}", idx, err),
+            }
+        }
+        for (tx, idx) in self.waiting.drain(..) {
+            let _ = tx.send(Response::Appended(idx));
+        }
+        self.sign_head();
+    }
+
+    // none of the waiting appends made it, so they all get the error. the state already has them
+    // applied, so the only way back is to start again from what is on disk: a failed commit takes
+    // their offsets back out of the offset file, so healing drops their entries. if even that can't
+    // be done there is nothing we can serve.
+    fn fail(&mut self, err: anyhow::Error) {
+        for (tx, _) in self.waiting.drain(..) {
+            let _ = tx.send(Response::Error(anyhow!("failed to commit: {}", err)));
+        }
+
+        match Background::load(
+            self.secret_key,
+            self.config.clone(),
+            self.broadcaster.clone(),
+        ) {
+            // a snapshot being written is still of committed entries, and still the only one
+            Ok(background) => {
+                let snapshotting = self.snapshotting.take();
+                *self = background;
+                self.snapshotting = snapshotting;
+            }
+            Err(err) => {
+                tracing::error!("failed to reload the log, exiting: {:#}", err);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    // everything is committed by the time this is called, so this is the state of the whole log.
+    // while the previous snapshot is still being written this waits for a later batch, so writes
+    // never overlap and there is never more than one copy of the state waiting to be written.
+    fn snapshot_if_due(&mut self) {
+        if self.ls.len() - self.snapshotted < self.config.snapshot_interval {
+            return;
+        }
+        if let Some(writing) = self.snapshotting.take() {
+            if !writing.is_finished() {
+                self.snapshotting = Some(writing);
+                return;
+            }
+            let _ = writing.join();
+        }
+
+        self.snapshotted = self.ls.len();
+        let snapshot = snapshot::Snapshot {
+            count: self.ls.len(),
+            hash: self
+                .ls
+                .head()
+                .map_or(cassis::log::GENESIS, |(_, hash)| hash),
+            state: self.state.clone(),
+            history: self.history.clone(),
+        };
+        let snapshots = self.config.snapshots();
+        self.snapshotting = Some(thread::spawn(move || {
+            if let Err(err) = snapshot::write(&snapshots, &snapshot) {
+                tracing::warn!("failed to write snapshot {}: {}", snapshot.count, err);
+            }
+        }));
+    }
+
+    fn answer(&self, req: Request) -> Response {
+        let (ls, state, tree, state_tree) = (&self.ls, &self.state, &self.tree, &self.state_tree);
+        match req {
+            Request::AppendOperation(_) | Request::AppendBatch(_) => {
+                unreachable!("appends are answered once committed")
+            }
+            Request::ListOperations(after, limit) => {
+                list(ls, after, limit).map_or_else(Response::Error, Response::Page)
+            }
+            Request::ReadOperation(id) => ls.read_operation(id).map_or_else(
+                |_| Response::Error(anyhow!("not found")),
+                Response::Operation,
+            ),
+            Request::GetKeyID(pubkey) => state.key_indexes.get(&pubkey).map_or_else(
+                || Response::Error(anyhow!("not found")),
+                |idx| Response::KeyIdx(*idx),
+            ),
+            Request::GetHead => self
+                .head
+                .clone()
+                .map_or_else(|| Response::Error(anyhow!("log is empty")), Response::Head),
+            Request::GetProof(id) => {
+                match (&self.head, tree.inclusion_proof(id as u64, tree.len())) {
+                    (Some(head), Some(path)) => Response::Proof(InclusionProof {
+                        idx: id,
+                        path,
+                        head: head.clone(),
+                    }),
+                    _ => Response::Error(anyhow!("not found")),
+                }
+            }
+            Request::GetConsistency(from, to) => tree.consistency_proof(from, to).map_or_else(
+                || Response::Error(anyhow!("not found")),
+                |path| Response::Consistency(ConsistencyProof { from, to, path }),
+            ),
+            Request::GetAccount(pubkey) => state
+                .key_indexes
+                .get(&pubkey)
+                .and_then(|idx| Account::build(state, &self.adjacency, *idx))
+                .map_or_else(|| Response::Error(anyhow!("not found")), Response::Account),
+            Request::GetAccountLog(pubkey, after, limit) => match state.key_indexes.get(&pubkey) {
+                None => Response::AccountLog(None),
+                Some(idx) => {
+                    let (indexes, more) = self.history.page(*idx, after, limit.clamp(1, MAX_PAGE));
+                    indexes
+                        .iter()
+                        .map(|idx| ls.read_entry(*idx))
+                        .collect::<Result<Vec<Entry>, _>>()
+                        .map_or_else(Response::Error, |entries| {
+                            Response::AccountLog(Some(Page {
+                                next: more.then(|| indexes.last().copied()).flatten(),
+                                entries,
+                            }))
                         })
-                        .map_or_else(|e| Response::Error(e), |_| Response::OK)
                 }
-                Request::ReadOperation(id) => ls.read_operation(id).map_or_else(
-                    |_| Response::Error(anyhow!("not found")),
-                    |op| Response::Operation(op),
-                ),
-                Request::GetKeyID(pubkey) => state.key_indexes.get(&pubkey).map_or_else(
-                    || Response::Error(anyhow!("not found")),
-                    |idx| Response::KeyIdx(*idx),
-                ),
-                Request::GetLines => {
-                    let mut lines: Vec<cassis::state::Line> = Vec::with_capacity(12);
-                    for (_, line) in state.lines.iter() {
-                        lines.push(line.clone());
-                    }
-                    Response::Lines(lines)
+            },
+            Request::GetLineProof(a, b) => match (&self.head, state_tree.line_proof(a, b)) {
+                (Some(head), Some(path)) => Response::LineProof(LineProof {
+                    line: state.lines[&cassis::state::Line::build_key(a, b)].clone(),
+                    keys_root: state_tree.keys_root(),
+                    path,
+                    head: head.clone(),
+                }),
+                _ => Response::Error(anyhow!("not found")),
+            },
+            Request::GetLines => {
+                let mut lines: Vec<cassis::state::Line> = Vec::with_capacity(12);
+                for (_, line) in state.lines.iter() {
+                    lines.push(line.clone());
                 }
-            };
-            req.0.send(resp).expect("failed to send response back");
+                Response::Lines(lines)
+            }
         }
+    }
+}
 
-        Ok(())
-    });
+// how many requests are taken from the channel at once
+const MAX_BATCH: usize = 256;
+
+// how many committed entries are kept for subscribers that haven't taken them yet. one that falls
+// further behind misses some and has to read them from the log instead.
+const BROADCAST_CAPACITY: usize = 1024;
+
+// the most operations a single batch can append, each one is validated on a copy of the state
+pub const MAX_BATCH_OPS: usize = 256;
+
+// the most entries given back in a single page, whatever was asked for
+pub const MAX_PAGE: usize = 500;
+
+// `next` is what to pass as `after` to get the following page, if there is one
+#[derive(Debug, serde::Serialize)]
+pub struct Page {
+    pub entries: Vec<Entry>,
+    pub next: Option<u32>,
+}
 
-    Requester { sender: tx }
+// up to `limit` committed entries after `after`, or from the start
+fn list(ls: &LogStore, after: Option<u32>, limit: usize) -> Result<Page, anyhow::Error> {
+    let start = after.map_or(0, |after| after.saturating_add(1));
+    let end = start.saturating_add(limit.clamp(1, MAX_PAGE) as u32);
+    let entries: Vec<Entry> = ls
+        .range(start..end)?
+        .enumerate()
+        .map(|(i, op)| {
+            let idx = start + i as u32;
+            Ok(Entry {
+                idx,
+                hash: ls.chain_hash(idx)?,
+                op: op?,
+            })
+        })
+        .collect::<Result<_, anyhow::Error>>()?;
+
+    Ok(Page {
+        next: entries
+            .last()
+            .map(|entry| entry.idx)
+            .filter(|idx| idx + 1 < ls.len()),
+        entries,
+    })
 }
 
 #[derive(Debug)]
 enum Request {
     AppendOperation(cassis::Operation),
-    ListOperations(Option<u32>, Option<u32>),
+    AppendBatch(Vec<cassis::Operation>),
+    ListOperations(Option<u32>, usize),
     GetKeyID([u8; 32]),
     ReadOperation(u32),
+    GetHead,
+    GetProof(u32),
+    GetConsistency(u64, u64),
+    GetLineProof(u32, u32),
+    GetAccount([u8; 32]),
+    GetAccountLog([u8; 32], Option<u32>, usize),
     GetLines,
 }
 
 #[derive(Debug)]
 enum Response {
-    OK,
+    Appended(u32),
     Operation(cassis::Operation),
-    Operations(Vec<cassis::Operation>),
+    Page(Page),
     Lines(Vec<cassis::state::Line>),
     KeyIdx(u32),
+    Head(Head),
+    Proof(InclusionProof),
+    Consistency(ConsistencyProof),
+    LineProof(LineProof),
+    Account(Account),
+    AccountLog(Option<Page>),
     Error(anyhow::Error),
 }
 
+// why a batch was rejected: the first operation in it that couldn't be applied
+#[derive(Debug)]
+pub struct BatchError {
+    pub index: usize,
+    pub error: ValidationError,
+}
+
+impl fmt::Display for BatchError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "operation {} in batch: {}", self.index, self.error)
+    }
+}
+
+impl std::error::Error for BatchError {}
+
+#[derive(Clone)]
 pub struct Requester {
     sender: mpsc::Sender<(oneshot::Sender<Response>, Request)>,
+    broadcaster: broadcast::Sender<Entry>,
 }
 
 impl Requester {
@@ -108,21 +498,28 @@ impl Requester {
         rx.await.expect("failed to receive state from oneshot")
     }
 
-    pub async fn append_operation(&self, op: cassis::Operation) -> Result<(), anyhow::Error> {
+    // the index the operation got in the log
+    pub async fn append_operation(&self, op: cassis::Operation) -> Result<u32, anyhow::Error> {
         match self.request(Request::AppendOperation(op)).await {
-            Response::OK => Ok(()),
+            Response::Appended(idx) => Ok(idx),
             Response::Error(err) => Err(err),
             _ => panic!("got unexpected response!"),
         }
     }
 
-    pub async fn list(
-        &self,
-        from: Option<u32>,
-        to: Option<u32>,
-    ) -> Result<Vec<cassis::Operation>, anyhow::Error> {
-        match self.request(Request::ListOperations(from, to)).await {
-            Response::Operations(ops) => Ok(ops),
+    // the index the first operation got in the log, the others follow it
+    pub async fn append_batch(&self, ops: Vec<cassis::Operation>) -> Result<u32, anyhow::Error> {
+        match self.request(Request::AppendBatch(ops)).await {
+            Response::Appended(idx) => Ok(idx),
+            Response::Error(err) => Err(err),
+            _ => panic!("got unexpected response!"),
+        }
+    }
+
+    // the entries after `after`, or from the start, up to `limit` or `MAX_PAGE`
+    pub async fn list(&self, after: Option<u32>, limit: usize) -> Result<Page, anyhow::Error> {
+        match self.request(Request::ListOperations(after, limit)).await {
+            Response::Page(page) => Ok(page),
             Response::Error(err) => Err(err),
             _ => panic!("got unexpected response!"),
         }
@@ -142,6 +539,63 @@ impl Requester {
         }
     }
 
+    pub async fn get_head(&self) -> Option<Head> {
+        match self.request(Request::GetHead).await {
+            Response::Head(head) => Some(head),
+            _ => None,
+        }
+    }
+
+    pub async fn get_proof(&self, id: u32) -> Option<InclusionProof> {
+        match self.request(Request::GetProof(id)).await {
+            Response::Proof(proof) => Some(proof),
+            _ => None,
+        }
+    }
+
+    pub async fn get_consistency(&self, from: u64, to: u64) -> Option<ConsistencyProof> {
+        match self.request(Request::GetConsistency(from, to)).await {
+            Response::Consistency(proof) => Some(proof),
+            _ => None,
+        }
+    }
+
+    pub async fn get_line_proof(&self, a: u32, b: u32) -> Option<LineProof> {
+        match self.request(Request::GetLineProof(a, b)).await {
+            Response::LineProof(proof) => Some(proof),
+            _ => None,
+        }
+    }
+
+    pub async fn get_account(&self, pubkey: [u8; 32]) -> Option<Account> {
+        match self.request(Request::GetAccount(pubkey)).await {
+            Response::Account(account) => Some(account),
+            _ => None,
+        }
+    }
+
+    pub async fn get_account_log(
+        &self,
+        pubkey: [u8; 32],
+        after: Option<u32>,
+        limit: usize,
+    ) -> Result<Option<Page>, anyhow::Error> {
+        match self
+            .request(Request::GetAccountLog(pubkey, after, limit))
+            .await
+        {
+            Response::AccountLog(page) => Ok(page),
+            Response::Error(err) => Err(err),
+            _ => panic!("got unexpected response!"),
+        }
+    }
+
+    // every entry committed from now on, in order. subscribe before listing the log to follow it
+    // without missing anything in between, and list it again after `RecvError::Lagged`.
+    pub fn subscribe(&self) -> broadcast::Receiver<Entry> {
+        self.broadcaster.subscribe()
+    }
+
     // this is temporary, for debugging purposes
     pub async fn get_lines(&self) -> Vec<cassis::state::Line> {
         match self.request(Request::GetLines).await {
@@ -150,3 +604,387 @@ impl Requester {
         }
     }
 }
+
+// everything after `after` one page at a time, then whatever comes through `listener` as it comes.
+// `listener` has to be subscribed before calling this, so every entry is either in a page or still
+// waiting in it, and the ones that are in both are skipped the second time. when we fall too far
+// behind the listener we go back to pages until we catch up again.
+pub fn follow(
+    requester: Requester,
+    mut listener: broadcast::Receiver<Entry>,
+    mut after: Option<u32>,
+) -> impl Stream<Item = Entry> {
+    async_stream::stream! {
+        let mut behind = true;
+        loop {
+            if behind {
+                match requester.list(after, MAX_PAGE).await {
+                    Ok(page) => {
+                        for entry in page.entries {
+                            after = Some(entry.idx);
+                            yield entry;
+                        }
+                        behind = page.next.is_some();
+                    }
+                    Err(err) => {
+                        tracing::warn!("failed to list log after {:?}: {}", after, err);
+                        break;
+                    }
+                }
+                continue;
+            }
+
+            match listener.recv().await {
+                Ok(entry) => {
+                    if after.is_some_and(|after| entry.idx <= after) {
+                        continue;
+                    }
+                    after = Some(entry.idx);
+                    yield entry;
+                }
+                Err(broadcast::error::RecvError::Lagged(missed)) => {
+                    tracing::debug!("log listener missed {} entries after {:?}", missed, after);
+                    behind = true;
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::background::common::{config, open_store, start_in};
+    use cassis::{
+        test_utils::{hop, secret, signed_transfer, trust},
+        Operation,
+    };
+    use std::time::Duration;
+
+    // what the background thread runs on, to be driven one request at a time
+    fn load(config: Config) -> Background {
+        let key: &'static cassis::SecretKey = Box::leak(Box::new(secret(0)));
+        let (broadcaster, _) = broadcast::channel(BROADCAST_CAPACITY);
+        Background::load(key, config, broadcaster).unwrap()
+    }
+
+    #[test]
+    fn pages_follow_each_other_until_the_end() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut ls = open_store(dir.path(), Durability::PerOp);
+
+        let empty = list(&ls, None, 10).unwrap();
+        assert!(empty.entries.is_empty());
+        assert_eq!(empty.next, None);
+
+        // the store doesn't validate anything
+        let ops: Vec<Operation> = (0..7).map(|i| trust(0, 1, i)).collect();
+        for op in ops.iter() {
+            ls.append_operation(op).unwrap();
+        }
+
+        let idxs = |page: &Page| page.entries.iter().map(|e| e.idx).collect::<Vec<_>>();
+        let first = list(&ls, None, 3).unwrap();
+        assert_eq!(idxs(&first), [0, 1, 2]);
+        assert_eq!(first.entries[1].op, ops[1]);
+        assert!(first.entries[0].follows(&cassis::log::GENESIS));
+        assert!(first.entries[1].follows(&first.entries[0].hash));
+        assert_eq!(first.next, Some(2));
+        let second = list(&ls, first.next, 3).unwrap();
+        assert_eq!(idxs(&second), [3, 4, 5]);
+        assert!(second.entries[0].follows(&first.entries[2].hash));
+        let last = list(&ls, second.next, 3).unwrap();
+        assert_eq!(idxs(&last), [6]);
+        assert_eq!(last.next, None);
+
+        // exactly at the end there is nothing more to ask for
+        assert_eq!(list(&ls, Some(3), 3).unwrap().next, None);
+        assert!(list(&ls, Some(6), 3).unwrap().entries.is_empty());
+        assert!(list(&ls, Some(u32::MAX), 3).unwrap().entries.is_empty());
+
+        // limits are kept between 1 and MAX_PAGE
+        assert_eq!(idxs(&list(&ls, None, 0).unwrap()), [0]);
+        assert_eq!(list(&ls, None, usize::MAX).unwrap().entries.len(), 7);
+    }
+
+    #[tokio::test]
+    async fn account_log_survives_a_restart_from_a_snapshot() {
+        let dir = tempfile::tempdir().unwrap();
+        let requester = start_in(dir.path(), 4);
+        for op in [
+            trust(0, 1, 10),
+            trust(0, 2, 20),
+            trust(1, 2, 5),
+            trust(0, 1, 30),
+        ] {
+            requester.append_operation(op).await.unwrap();
+        }
+
+        // the snapshot is written in the background
+        let snapshot = dir.path().join("snapshots").join("snapshot-0000000004");
+        for _ in 0..100 {
+            if snapshot.exists() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert!(snapshot.exists());
+        let after = trust(1, 3, 7);
+        requester.append_operation(after.clone()).await.unwrap();
+        drop(requester);
+
+        // only what came after the snapshot is replayed, the rest of the history is in it
+        let requester = start_in(dir.path(), 4);
+        let page = requester
+            .get_account_log(secret(1).public().serialize(), None, 10)
+            .await
+            .unwrap()
+            .unwrap();
+        let idxs: Vec<u32> = page.entries.iter().map(|entry| entry.idx).collect();
+        assert_eq!(idxs, [0, 2, 3, 4]);
+        assert_eq!(page.entries[3].op, after);
+    }
+
+    #[test]
+    fn failed_commits_are_answered_and_leave_what_is_on_disk() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = config(dir.path(), 100);
+        config.durability = Durability::Group;
+        let mut background = load(config);
+
+        let (tx, mut rx) = oneshot::channel();
+        background.append_operation(tx, trust(0, 1, 10));
+        background.commit();
+        assert!(matches!(rx.try_recv(), Ok(Response::Appended(0))));
+        let (state, head) = (background.state.clone(), background.head.clone().unwrap());
+
+        let (tx, mut rx) = oneshot::channel();
+        background.append_operation(tx, trust(0, 2, 20));
+        let (batch_tx, mut batch_rx) = oneshot::channel();
+        background.append_batch(batch_tx, vec![trust(1, 2, 5), trust(0, 1, 30)]);
+        background.fail(anyhow!("disk full"));
+
+        assert!(matches!(rx.try_recv(), Ok(Response::Error(_))));
+        assert!(matches!(batch_rx.try_recv(), Ok(Response::Error(_))));
+        assert!(background.waiting.is_empty());
+        assert_eq!(background.ls.next_idx(), 1);
+        assert_eq!(background.tree.len(), 1);
+        assert_eq!(background.state, state);
+        let after = background.head.unwrap();
+        assert_eq!(
+            (after.idx, after.hash, after.root, after.state_root),
+            (head.idx, head.hash, head.root, head.state_root)
+        );
+    }
+
+    #[test]
+    fn appends_whose_commit_fails_are_not_in_the_reloaded_log() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = config(dir.path(), 100);
+        config.durability = Durability::Group;
+        let mut background = load(config);
+
+        let (tx, _rx) = oneshot::channel();
+        background.append_operation(tx, trust(0, 1, 10));
+        background.commit();
+        let (state, head) = (background.state.clone(), background.head.clone().unwrap());
+
+        // the offsets are written, but syncing them fails
+        let (tx, mut rx) = oneshot::channel();
+        background.append_operation(tx, trust(0, 2, 20));
+        let (batch_tx, mut batch_rx) = oneshot::channel();
+        background.append_batch(batch_tx, vec![trust(1, 2, 5), trust(0, 1, 30)]);
+        background.ls.fail_offset_syncs();
+        background.commit();
+
+        assert!(matches!(rx.try_recv(), Ok(Response::Error(_))));
+        assert!(matches!(batch_rx.try_recv(), Ok(Response::Error(_))));
+        assert_eq!((background.ls.len(), background.ls.next_idx()), (1, 1));
+        assert_eq!(background.tree.len(), 1);
+        assert_eq!(background.state, state);
+        assert_eq!(background.state_tree.root(), head.state_root);
+        let after = background.head.clone().unwrap();
+        assert_eq!(
+            (after.idx, after.hash, after.root, after.state_root),
+            (head.idx, head.hash, head.root, head.state_root)
+        );
+
+        // and what comes next takes their place
+        let (tx, mut rx) = oneshot::channel();
+        background.append_operation(tx, trust(0, 3, 40));
+        background.commit();
+        assert!(matches!(rx.try_recv(), Ok(Response::Appended(1))));
+        assert_eq!(background.ls.len(), 2);
+    }
+
+    #[test]
+    fn snapshots_are_written_one_at_a_time() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut background = load(config(dir.path(), 1));
+
+        // every batch is due a snapshot, most of them come while the last one is being written
+        let mut taken = vec![];
+        for amount in 1..=50 {
+            let (tx, _rx) = oneshot::channel();
+            background.append_operation(tx, trust(0, 1, amount));
+            background.commit();
+            background.snapshot_if_due();
+            if taken.last() != Some(&background.snapshotted) {
+                taken.push(background.snapshotted);
+            }
+        }
+        background.snapshotting.take().unwrap().join().unwrap();
+
+        // the newest one taken is there and readable, and nothing else is left around
+        let snapshots = background.config.snapshots();
+        let latest = snapshot::load_latest(&snapshots, |_| true)
+            .unwrap()
+            .unwrap();
+        assert_eq!(latest.count, *taken.last().unwrap());
+        assert_eq!(
+            latest.hash,
+            background.ls.chain_hash(latest.count - 1).unwrap()
+        );
+        let left: Vec<_> = std::fs::read_dir(&snapshots)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        assert!(left.len() <= 2, "{:?}", left);
+        assert!(
+            left.iter().all(|name| name.starts_with("snapshot-")),
+            "{:?}",
+            left
+        );
+    }
+
+    #[test]
+    fn failing_operation_in_a_batch_leaves_everything_before_it_out() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut background = load(config(dir.path(), 100));
+        let (tx, _rx) = oneshot::channel();
+        background.append_operation(tx, trust(0, 1, 10));
+        background.commit();
+        let (state, head) = (background.state.clone(), background.head.clone().unwrap());
+
+        // the first two are fine, the third can't be sent with what the second one trusts
+        let (tx, mut rx) = oneshot::channel();
+        background.append_batch(
+            tx,
+            vec![
+                trust(0, 2, 20),
+                trust(1, 2, 5),
+                signed_transfer(vec![hop(2, 1, 6)]),
+                trust(0, 3, 30),
+            ],
+        );
+        background.commit();
+
+        match rx.try_recv() {
+            Ok(Response::Error(err)) => {
+                let err = err.downcast::<BatchError>().unwrap();
+                assert_eq!(err.index, 2);
+                assert!(matches!(
+                    err.error,
+                    ValidationError::InsufficientCredit { hop: 0, .. }
+                ));
+            }
+            _ => panic!("batch should have been rejected"),
+        }
+        assert_eq!(background.ls.len(), 1);
+        assert_eq!(background.ls.next_idx(), 1);
+        assert_eq!(background.tree.len(), 1);
+        assert_eq!(background.state, state);
+        assert_eq!(background.state_tree.root(), head.state_root);
+        let after = background.head.unwrap();
+        assert_eq!((after.idx, after.hash), (head.idx, head.hash));
+        assert!(list(&background.ls, Some(0), 10)
+            .unwrap()
+            .entries
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn committed_entries_reach_subscribers() {
+        let dir = tempfile::tempdir().unwrap();
+        // syncing every append, so they are committed before the background commits them
+        let requester = start_in(dir.path(), 1000);
+        let mut listener = requester.subscribe();
+
+        let last = trust(1, 2, 5);
+        let first = requester.append_operation(trust(0, 1, 10)).await.unwrap();
+        let batch = requester
+            .append_batch(vec![trust(0, 2, 20), last.clone()])
+            .await
+            .unwrap();
+        assert_eq!((first, batch), (0, 1));
+
+        let mut heard = vec![];
+        for _ in 0..3 {
+            heard.push(
+                tokio::time::timeout(Duration::from_secs(5), listener.recv())
+                    .await
+                    .expect("nothing was broadcast")
+                    .unwrap(),
+            );
+        }
+        let idxs: Vec<u32> = heard.iter().map(|entry| entry.idx).collect();
+        assert_eq!(idxs, [0, 1, 2]);
+        assert_eq!(heard[2].op, last);
+        assert!(heard[0].follows(&cassis::log::GENESIS));
+        assert!(heard[1].follows(&heard[0].hash));
+        assert!(listener.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn following_picks_up_from_the_store_after_lagging() {
+        use futures::StreamExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let requester = start_in(dir.path(), 1000);
+        for amount in 1..=4 {
+            requester
+                .append_operation(trust(0, 1, amount))
+                .await
+                .unwrap();
+        }
+
+        // a listener that can only hold two entries, fed by hand
+        let (sender, listener) = broadcast::channel(2);
+        let mut stream = Box::pin(follow(requester.clone(), listener, None));
+        let mut seen = vec![];
+        for _ in 0..4 {
+            seen.push(stream.next().await.unwrap());
+        }
+
+        // six more are committed while nobody reads the listener, so it loses the first four
+        for amount in 5..=10 {
+            requester
+                .append_operation(trust(0, 1, amount))
+                .await
+                .unwrap();
+        }
+        for entry in requester.list(Some(3), 10).await.unwrap().entries {
+            let _ = sender.send(entry);
+        }
+        for _ in 0..6 {
+            seen.push(stream.next().await.unwrap());
+        }
+
+        // and then it goes on with what it hears, skipping what it already got from the store
+        let last = requester.append_operation(trust(0, 1, 11)).await.unwrap();
+        let _ = sender.send(requester.list(Some(last - 1), 1).await.unwrap().entries[0].clone());
+        seen.push(stream.next().await.unwrap());
+
+        let idxs: Vec<u32> = seen.iter().map(|entry| entry.idx).collect();
+        assert_eq!(idxs, (0..=10).collect::<Vec<u32>>());
+        assert!(seen[0].follows(&cassis::log::GENESIS));
+        for pair in seen.windows(2) {
+            assert!(pair[1].follows(&pair[0].hash));
+        }
+
+        // nothing else is left to come
+        drop(sender);
+        assert!(stream.next().await.is_none());
+    }
+}
@@ -0,0 +1,268 @@
+use anyhow::{anyhow, Context};
+use byteorder::{ByteOrder, LE};
+use cassis::State;
+
+use super::history::History;
+use secp256k1::hashes::{sha256, Hash};
+use std::{
+    fs,
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+// how many snapshots we keep around, older ones are deleted when a new one is written
+const KEEP: usize = 2;
+
+const PREFIX: &str = "snapshot-";
+// what snapshots are written as before they are complete
+const TMP_PREFIX: &str = "tmp-snapshot-";
+
+// the state and history after applying the first `count` entries of the log, and the chain hash
+// of the last of them so we can tell if it is still the same log
+pub struct Snapshot {
+    pub count: u32,
+    pub hash: [u8; 32],
+    pub state: State,
+    pub history: History,
+}
+
+// [count][hash][state as `State::write_to` has it][history as `History::write_to` has it]
+// [sha256 of everything before]
+impl Snapshot {
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        buf.extend_from_slice(&self.count.to_le_bytes());
+        buf.extend_from_slice(&self.hash);
+        self.state
+            .write_to(&mut buf)
+            .expect("writing to a vec doesn't fail");
+        self.history.write_to(&mut buf);
+        let checksum = sha256::Hash::hash(&buf).to_byte_array();
+        buf.extend_from_slice(&checksum);
+        buf
+    }
+
+    fn decode(buf: &[u8]) -> Result<Self, anyhow::Error> {
+        if buf.len() < 4 + 32 + 32 {
+            return Err(anyhow!("snapshot too short"));
+        }
+        let (body, checksum) = buf.split_at(buf.len() - 32);
+        if sha256::Hash::hash(body).to_byte_array() != checksum {
+            return Err(anyhow!("snapshot checksum doesn't match"));
+        }
+
+        let count = LE::read_u32(&body[0..4]);
+        let hash: [u8; 32] = body[4..36].try_into().unwrap();
+        let mut rest = &body[36..];
+        let state = State::read_from(&mut rest)?;
+        let history = History::read_from(&mut rest)?;
+        if !rest.is_empty() {
+            return Err(anyhow!("{} unexpected bytes after the history", rest.len()));
+        }
+
+        Ok(Snapshot {
+            count,
+            hash,
+            state,
+            history,
+        })
+    }
+}
+
+fn path_for(dir: &Path, count: u32) -> PathBuf {
+    // zero-padded so they sort by count
+    dir.join(format!("{}{:010}", PREFIX, count))
+}
+
+// snapshot files in `dir`, newest first
+fn list(dir: &Path) -> Result<Vec<PathBuf>, anyhow::Error> {
+    let mut paths = Vec::new();
+    for entry in fs::read_dir(dir).context("failed to list snapshots")? {
+        let entry = entry?;
+        if entry.file_name().to_string_lossy().starts_with(PREFIX) {
+            paths.push(entry.path());
+        }
+    }
+    paths.sort();
+    paths.reverse();
+    Ok(paths)
+}
+
+// written to a temporary file first and then renamed, so a crash never leaves half a snapshot
+// under a snapshot name. only one is written at a time, so any temporary file already there was
+// left by a write that was interrupted.
+pub fn write(dir: &Path, snapshot: &Snapshot) -> Result<(), anyhow::Error> {
+    fs::create_dir_all(dir).context("failed to create snapshots directory")?;
+    for entry in fs::read_dir(dir).context("failed to list snapshots")? {
+        let entry = entry?;
+        if entry.file_name().to_string_lossy().starts_with(TMP_PREFIX) {
+            fs::remove_file(entry.path()).context("failed to remove interrupted snapshot")?;
+        }
+    }
+
+    let path = path_for(dir, snapshot.count);
+    let tmp = dir.join(format!("{}{:010}", TMP_PREFIX, snapshot.count));
+    let mut file = fs::File::create(&tmp).context("failed to create snapshot file")?;
+    file.write_all(&snapshot.encode())
+        .context("failed to write snapshot")?;
+    file.sync_all().context("failed to sync snapshot")?;
+    fs::rename(&tmp, &path).context("failed to rename snapshot")?;
+    fs::File::open(dir)
+        .and_then(|dir| dir.sync_all())
+        .context("failed to sync snapshots directory")?;
+
+    for old in list(dir)?.into_iter().skip(KEEP) {
+        fs::remove_file(&old)
+            .with_context(|| format!("failed to remove old snapshot {}", old.display()))?;
+    }
+
+    Ok(())
+}
+
+// the newest snapshot that can be read and that `is_valid` accepts, if any
+pub fn load_latest(
+    dir: &Path,
+    is_valid: impl Fn(&Snapshot) -> bool,
+) -> Result<Option<Snapshot>, anyhow::Error> {
+    if !dir.exists() {
+        return Ok(None);
+    }
+
+    for path in list(dir)? {
+        let snapshot = fs::read(&path)
+            .context("failed to read snapshot")
+            .and_then(|buf| Snapshot::decode(&buf));
+        match snapshot {
+            Ok(snapshot) if is_valid(&snapshot) => return Ok(Some(snapshot)),
+            Ok(_) => tracing::warn!("snapshot {} doesn't match the log", path.display()),
+            Err(err) => tracing::warn!("snapshot {} is broken: {}", path.display(), err),
+        }
+    }
+
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cassis::{
+        state::Line,
+        test_utils::{secret, trust},
+    };
+    use std::collections::HashMap;
+
+    fn state(nkeys: u32) -> State {
+        let mut state = State {
+            keys: vec![],
+            key_indexes: HashMap::new(),
+            lines: HashMap::default(),
+        };
+        for idx in 0..nkeys {
+            let key = secret(idx).public();
+            state.keys.push(key);
+            state.key_indexes.insert(key.serialize(), idx);
+        }
+        for idx in 1..nkeys {
+            state.lines.insert(
+                Line::build_key(0, idx),
+                Line {
+                    peers: (0, idx),
+                    trust: (idx, idx * 10),
+                    balance: -(idx as i64),
+                },
+            );
+        }
+        state
+    }
+
+    fn snapshot(count: u32) -> Snapshot {
+        let state = state(count + 1);
+        let mut history = History::default();
+        for idx in 0..count {
+            history.add(&state, idx, &trust(0, idx + 1, idx));
+        }
+        Snapshot {
+            count,
+            hash: [count as u8; 32],
+            state,
+            history,
+        }
+    }
+
+    #[test]
+    fn snapshot_round_trips() {
+        let original = snapshot(7);
+        let decoded = Snapshot::decode(&original.encode()).unwrap();
+
+        assert_eq!(decoded.count, original.count);
+        assert_eq!(decoded.hash, original.hash);
+        assert_eq!(decoded.state, original.state);
+        assert_eq!(decoded.history, original.history);
+    }
+
+    #[test]
+    fn corrupted_snapshot_is_rejected() {
+        let buf = snapshot(3).encode();
+        for at in 0..buf.len() {
+            let mut corrupted = buf.clone();
+            corrupted[at] ^= 1;
+            assert!(Snapshot::decode(&corrupted).is_err(), "flipped byte {}", at);
+        }
+        for len in 0..buf.len() {
+            assert!(Snapshot::decode(&buf[..len]).is_err(), "cut at {}", len);
+        }
+    }
+
+    #[test]
+    fn latest_valid_snapshot_is_loaded() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(load_latest(dir.path(), |_| true).unwrap().is_none());
+
+        for count in [10, 20, 30] {
+            write(dir.path(), &snapshot(count)).unwrap();
+        }
+        // only the newest ones are kept
+        assert_eq!(list(dir.path()).unwrap().len(), KEEP);
+        assert_eq!(
+            load_latest(dir.path(), |_| true).unwrap().unwrap().count,
+            30
+        );
+
+        // one that doesn't match the log is skipped
+        let older = load_latest(dir.path(), |snapshot| snapshot.count < 30).unwrap();
+        assert_eq!(older.unwrap().count, 20);
+
+        // and so is a broken one
+        let path = path_for(dir.path(), 30);
+        let mut buf = fs::read(&path).unwrap();
+        buf[5] ^= 1;
+        fs::write(&path, buf).unwrap();
+        assert_eq!(
+            load_latest(dir.path(), |_| true).unwrap().unwrap().count,
+            20
+        );
+    }
+
+    #[test]
+    fn interrupted_write_leaves_the_newest_snapshot_readable() {
+        let dir = tempfile::tempdir().unwrap();
+        write(dir.path(), &snapshot(10)).unwrap();
+
+        // stopped halfway through writing the next one
+        let buf = snapshot(20).encode();
+        let tmp = dir.path().join(format!("{}{:010}", TMP_PREFIX, 20));
+        fs::write(&tmp, &buf[..buf.len() / 2]).unwrap();
+        assert_eq!(
+            load_latest(dir.path(), |_| true).unwrap().unwrap().count,
+            10
+        );
+
+        // the next write clears what was left
+        write(dir.path(), &snapshot(30)).unwrap();
+        assert!(!tmp.exists());
+        assert_eq!(
+            load_latest(dir.path(), |_| true).unwrap().unwrap().count,
+            30
+        );
+    }
+}
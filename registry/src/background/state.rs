@@ -1,33 +1,142 @@
-use secp256k1::hashes::{sha256, Hash};
-use std::{collections::HashMap, hash::BuildHasherDefault};
+use anyhow::Context;
+use std::{collections::HashMap, hash::BuildHasherDefault, path::Path};
 
-use crate::background::LogStore;
+use crate::background::{history::History, snapshot, LogStore};
 
-pub fn init(initial_key: cassis::PublicKey, ls: &LogStore) -> Result<cassis::State, anyhow::Error> {
-    let mut state = cassis::State {
-        keys: vec![initial_key],
-        key_indexes: HashMap::with_capacity(500),
-        lines: HashMap::with_capacity_and_hasher(1000, BuildHasherDefault::default()),
-    };
+// starts from the latest snapshot that matches the log, if there is one, and replays the entries
+// that came after it on both the state and the history. also returns how many entries that
+// snapshot covered.
+pub fn init(
+    initial_key: cassis::PublicKey,
+    ls: &LogStore,
+    snapshots: &Path,
+) -> Result<(cassis::State, History, u32), anyhow::Error> {
+    let latest = snapshot::load_latest(snapshots, |snapshot| {
+        snapshot.state.keys.first() == Some(&initial_key)
+            && snapshot.count <= ls.len()
+            && match snapshot.count {
+                0 => true,
+                count => ls.chain_hash(count - 1).ok() == Some(snapshot.hash),
+            }
+    })?;
 
-    state.key_indexes.insert(initial_key.serialize(), 0);
+    let (mut state, mut history, count) = match latest {
+        Some(snapshot) => (snapshot.state, snapshot.history, snapshot.count),
+        None => {
+            let mut state = cassis::State {
+                keys: vec![initial_key],
+                key_indexes: HashMap::with_capacity(500),
+                lines: HashMap::with_capacity_and_hasher(1000, BuildHasherDefault::default()),
+            };
+            state.key_indexes.insert(initial_key.serialize(), 0);
+            (state, History::default(), 0)
+        }
+    };
 
-    for op in ls.iter() {
+    let tail = match count {
+        0 => ls.iter(),
+        _ if count == ls.len() => return Ok((state, history, count)),
+        _ => ls.range(count..)?,
+    };
+    for (i, op) in tail.enumerate() {
+        let op = op.with_context(|| format!("failed to replay entry {}", count + i as u32))?;
         cassis::state::process(&mut state, &op);
+        history.add(&state, count + i as u32, &op);
     }
-    Ok(state)
+    Ok((state, history, count))
 }
 
-pub fn hash_and_sign_log_entry(
-    secret_key: cassis::SecretKey,
-    op: &cassis::Operation,
-    previous_entry_hash: [u8; 32],
-) -> cassis::SecretKey {
-    let op_sighash = sha256::Hash::hash(&op.sighash());
-    let mut concat = [0u8; 64];
-    concat[0..32].copy_from_slice(op_sighash.as_byte_array());
-    concat[32..64].copy_from_slice(&previous_entry_hash);
-    // let digest = sha256::Hash::hash(&concat);
-    // let message = Message::from_digest(digest.to_byte_array());
-    secret_key
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::background::{common::open_store, db::Durability, snapshot::Snapshot};
+    use cassis::{
+        test_utils::{secret, trust},
+        Operation,
+    };
+
+    #[test]
+    fn snapshot_plus_tail_is_the_same_as_replaying_everything() {
+        let dir = tempfile::tempdir().unwrap();
+        let snapshots = dir.path().join("snapshots");
+        let mut ls = open_store(dir.path(), Durability::PerOp);
+
+        let ops: Vec<Operation> = (1..8).map(|i| trust(0, i, i)).collect();
+        for op in ops[..4].iter() {
+            ls.append_operation(op).unwrap();
+        }
+        let (state, history, count) = init(secret(0).public(), &ls, &snapshots).unwrap();
+        assert_eq!(count, 0);
+        snapshot::write(
+            &snapshots,
+            &Snapshot {
+                count: 4,
+                hash: ls.chain_hash(3).unwrap(),
+                state,
+                history,
+            },
+        )
+        .unwrap();
+
+        for op in ops[4..].iter() {
+            ls.append_operation(op).unwrap();
+        }
+        let (from_snapshot, history, count) = init(secret(0).public(), &ls, &snapshots).unwrap();
+        assert_eq!(count, 4);
+        let (replayed, replayed_history, _) =
+            init(secret(0).public(), &ls, &dir.path().join("none")).unwrap();
+        assert_eq!(from_snapshot, replayed);
+        assert_eq!(history, replayed_history);
+        assert_eq!(replayed.keys.len(), 8);
+        // the operations before the snapshot only come from it
+        assert_eq!(history.page(0, None, 10).0, [0, 1, 2, 3, 4, 5, 6]);
+    }
+
+    #[test]
+    fn snapshot_of_another_log_is_ignored() {
+        let dir = tempfile::tempdir().unwrap();
+        let snapshots = dir.path().join("snapshots");
+        let mut ls = open_store(dir.path(), Durability::PerOp);
+        ls.append_operation(&trust(0, 1, 10)).unwrap();
+
+        let (state, history, _) = init(secret(0).public(), &ls, &snapshots).unwrap();
+        let mut wrong = state.clone();
+        wrong.keys.push(secret(5).public());
+        snapshot::write(
+            &snapshots,
+            &Snapshot {
+                count: 1,
+                hash: [1; 32],
+                state: wrong,
+                history,
+            },
+        )
+        .unwrap();
+
+        let (loaded, _, count) = init(secret(0).public(), &ls, &snapshots).unwrap();
+        assert_eq!(count, 0);
+        assert_eq!(loaded.keys, state.keys);
+    }
+
+    #[test]
+    fn broken_entry_fails_the_replay_instead_of_ending_it() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut ls = open_store(dir.path(), Durability::PerOp);
+        let ops: Vec<Operation> = (1..4).map(|i| trust(0, i, i)).collect();
+        for op in ops.iter() {
+            ls.append_operation(op).unwrap();
+        }
+        drop(ls);
+
+        // the tag of the second entry, healing only looks at the last one
+        let path = dir.path().join("log");
+        let mut log = std::fs::read(&path).unwrap();
+        log[2 + ops[0].size() + 2] = 0xff;
+        std::fs::write(&path, log).unwrap();
+
+        let ls = open_store(dir.path(), Durability::PerOp);
+        assert_eq!(ls.len(), 3);
+        let err = init(secret(0).public(), &ls, &dir.path().join("none")).unwrap_err();
+        assert!(format!("{:#}", err).contains("entry 1"), "{:#}", err);
+    }
 }
@@ -3,11 +3,9 @@ use axum::{
     response::{IntoResponse, Json},
     routing::{get, post},
 };
-use cassis::Operation;
-use futures::StreamExt;
+use cassis::{state::ValidationError, Operation};
 use lazy_static::lazy_static;
 use std::{env, sync::Arc};
-use tokio::sync::broadcast;
 
 mod background;
 
@@ -35,22 +33,41 @@ async fn main() {
         .finish();
     tracing::subscriber::set_global_default(subscriber).unwrap();
 
-    let requester = background::start(SERVER_KEY.public());
+    let requester = background::start(&SERVER_KEY, background::Config::from_env());
 
     let shared_state = Arc::new(GlobalContext { requester });
 
-    let (streamer, listener) = broadcast::channel::<serde_json::Value>(12);
-    let shared_listener = Arc::new(listener);
-
     let app = axum::Router::new()
         .route("/", get(|| async { "cassis-registry" }))
-        .route("/append", post(append_op).layer(axum::Extension(streamer)))
+        .route("/append", post(append_op))
+        .route("/append/batch", post(append_batch))
         .route("/log/:op_id", get(read_op))
-        .route("/log", get(get_log).layer(axum::Extension(shared_listener)))
+        .route("/log", get(get_log))
         .route(
             "/idx/:pubkey",
             get(get_key_id).with_state(shared_state.clone()),
         )
+        .route("/head", get(get_head).with_state(shared_state.clone()))
+        .route(
+            "/proof/:op_id",
+            get(get_proof).with_state(shared_state.clone()),
+        )
+        .route(
+            "/consistency",
+            get(get_consistency).with_state(shared_state.clone()),
+        )
+        .route(
+            "/line/:a/:b/proof",
+            get(get_line_proof).with_state(shared_state.clone()),
+        )
+        .route(
+            "/account/:pubkey",
+            get(get_account).with_state(shared_state.clone()),
+        )
+        .route(
+            "/account/:pubkey/log",
+            get(get_account_log).with_state(shared_state.clone()),
+        )
         .route("/lines", get(get_lines).with_state(shared_state.clone()))
         .with_state(shared_state.clone());
 
@@ -64,62 +81,107 @@ async fn main() {
 
 async fn append_op(
     axum::extract::State(ctx): axum::extract::State<Arc<GlobalContext>>,
-    axum::extract::Extension(streamer): axum::extract::Extension<
-        broadcast::Sender<serde_json::Value>,
-    >,
     axum::extract::Json(op): axum::extract::Json<Operation>,
 ) -> axum::response::Response {
-    match ctx.requester.append_operation(op.clone()).await {
-        Err(err) => {
-            return (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response();
+    // whoever follows the log hears about it from the background once it is committed
+    match ctx.requester.append_operation(op).await {
+        Ok(_) => StatusCode::OK.into_response(),
+        Err(err) => match err.downcast_ref::<ValidationError>() {
+            Some(verr) => validation_error_response(verr, None),
+            None => (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response(),
+        },
+    }
+}
+
+async fn append_batch(
+    axum::extract::State(ctx): axum::extract::State<Arc<GlobalContext>>,
+    axum::extract::Json(ops): axum::extract::Json<Vec<Operation>>,
+) -> axum::response::Response {
+    if ops.is_empty() {
+        return (StatusCode::BAD_REQUEST, "empty batch").into_response();
+    }
+    if ops.len() > background::MAX_BATCH_OPS {
+        return (
+            StatusCode::PAYLOAD_TOO_LARGE,
+            format!(
+                "a batch can't have more than {} operations",
+                background::MAX_BATCH_OPS
+            ),
+        )
+            .into_response();
+    }
+
+    match ctx.requester.append_batch(ops).await {
+        Ok(_) => StatusCode::OK.into_response(),
+        Err(err) => match err.downcast_ref::<background::BatchError>() {
+            Some(berr) => validation_error_response(&berr.error, Some(berr.index)),
+            None => (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response(),
+        },
+    }
+}
+
+// `index` is the position of the operation in a batch
+fn validation_error_response(
+    err: &ValidationError,
+    index: Option<usize>,
+) -> axum::response::Response {
+    let status = match err {
+        ValidationError::UnknownOperation
+        | ValidationError::EmptyTransfer
+        | ValidationError::TooManyHops { .. }
+        | ValidationError::TooManySignatures { .. }
+        | ValidationError::DuplicateSigner { .. }
+        | ValidationError::ZeroAmountHop { .. } => StatusCode::BAD_REQUEST,
+        ValidationError::UnknownKey { .. } | ValidationError::NoLine { .. } => {
+            StatusCode::NOT_FOUND
+        }
+        ValidationError::SelfTrust { .. } => StatusCode::UNPROCESSABLE_ENTITY,
+        ValidationError::InsufficientCredit { .. } => StatusCode::CONFLICT,
+        ValidationError::MissingSigner { .. } | ValidationError::BadSignature { .. } => {
+            StatusCode::FORBIDDEN
         }
-        _ => {}
     };
 
-    // dispatch to listeners
-    let value = serde_json::to_value(op).unwrap();
-    streamer
-        .send(value.clone())
-        .expect("failed to send through channel");
+    // the error fields plus a human readable message
+    let mut body = serde_json::to_value(err).unwrap();
+    body["message"] = serde_json::Value::String(err.to_string());
+    if let Some(index) = index {
+        body["index"] = index.into();
+    }
 
-    StatusCode::OK.into_response()
+    (status, Json(body)).into_response()
 }
 
+// how many entries a page has when the request doesn't say
+const DEFAULT_PAGE: usize = 50;
+
 #[derive(serde::Deserialize)]
 struct GetLogParams {
-    from: Option<u32>,
-    to: Option<u32>,
+    after: Option<u32>,
+    limit: Option<usize>,
     pub live: Option<bool>,
 }
 
 async fn get_log(
     axum::extract::State(ctx): axum::extract::State<Arc<GlobalContext>>,
-    axum::extract::Extension(shared_listener): axum::extract::Extension<
-        Arc<broadcast::Receiver<serde_json::Value>>,
-    >,
     axum::extract::Query(qs): axum::extract::Query<GetLogParams>,
 ) -> axum::response::Response {
-    match ctx.requester.list(qs.from, qs.to).await {
-        Ok(ops) => {
-            let past_stream = async_stream::stream! {
-                for operation in ops {
-                    yield serde_json::to_value(operation).unwrap()
-                }
-            };
-
-            if qs.live == Some(true) {
-                let listener = shared_listener.resubscribe();
-                let future_stream =
-                    tokio_stream::wrappers::BroadcastStream::new(listener).map(|res| res.unwrap());
-
-                axum_streams::StreamBodyAs::json_nl(past_stream.chain(future_stream))
-                    .into_response()
-            } else {
-                axum_streams::StreamBodyAs::json_nl(past_stream).into_response()
-            }
-        }
-        Err(err) => (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response(),
+    if qs.live != Some(true) {
+        return match ctx
+            .requester
+            .list(qs.after, qs.limit.unwrap_or(DEFAULT_PAGE))
+            .await
+        {
+            Ok(page) => Json(page).into_response(),
+            Err(err) => (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response(),
+        };
     }
+
+    // subscribed before listing anything, so nothing committed in between is missed
+    let listener = ctx.requester.subscribe();
+    let stream = background::follow(ctx.requester.clone(), listener, qs.after);
+
+    axum_streams::StreamBodyAs::json_nl(stream).into_response()
 }
 
 async fn get_key_id(
@@ -147,9 +209,247 @@ async fn read_op(
     }
 }
 
+async fn get_head(
+    axum::extract::State(ctx): axum::extract::State<Arc<GlobalContext>>,
+) -> axum::response::Response {
+    match ctx.requester.get_head().await {
+        Some(head) => Json(head).into_response(),
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+async fn get_proof(
+    axum::extract::State(ctx): axum::extract::State<Arc<GlobalContext>>,
+    axum::extract::Path(op_id): axum::extract::Path<u32>,
+) -> axum::response::Response {
+    match ctx.requester.get_proof(op_id).await {
+        Some(proof) => Json(proof).into_response(),
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct GetConsistencyParams {
+    from: u64,
+    to: u64,
+}
+
+async fn get_consistency(
+    axum::extract::State(ctx): axum::extract::State<Arc<GlobalContext>>,
+    axum::extract::Query(qs): axum::extract::Query<GetConsistencyParams>,
+) -> axum::response::Response {
+    match ctx.requester.get_consistency(qs.from, qs.to).await {
+        Some(proof) => Json(proof).into_response(),
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+async fn get_line_proof(
+    axum::extract::State(ctx): axum::extract::State<Arc<GlobalContext>>,
+    axum::extract::Path((a, b)): axum::extract::Path<(u32, u32)>,
+) -> axum::response::Response {
+    match ctx.requester.get_line_proof(a, b).await {
+        Some(proof) => Json(proof).into_response(),
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+async fn get_account(
+    axum::extract::State(ctx): axum::extract::State<Arc<GlobalContext>>,
+    axum::extract::Path(pubkey): axum::extract::Path<String>,
+) -> axum::response::Response {
+    let mut pk = [0u8; 32];
+    if hex::decode_to_slice(pubkey, &mut pk).is_err() {
+        return StatusCode::BAD_REQUEST.into_response();
+    }
+
+    match ctx.requester.get_account(pk).await {
+        Some(account) => Json(account).into_response(),
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct GetAccountLogParams {
+    after: Option<u32>,
+    limit: Option<usize>,
+}
+
+async fn get_account_log(
+    axum::extract::State(ctx): axum::extract::State<Arc<GlobalContext>>,
+    axum::extract::Path(pubkey): axum::extract::Path<String>,
+    axum::extract::Query(qs): axum::extract::Query<GetAccountLogParams>,
+) -> axum::response::Response {
+    let mut pk = [0u8; 32];
+    if hex::decode_to_slice(pubkey, &mut pk).is_err() {
+        return StatusCode::BAD_REQUEST.into_response();
+    }
+
+    let limit = qs.limit.unwrap_or(DEFAULT_PAGE);
+    match ctx.requester.get_account_log(pk, qs.after, limit).await {
+        Ok(Some(page)) => Json(page).into_response(),
+        Ok(None) => StatusCode::NOT_FOUND.into_response(),
+        Err(err) => (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response(),
+    }
+}
+
 async fn get_lines(
     axum::extract::State(ctx): axum::extract::State<Arc<GlobalContext>>,
 ) -> axum::response::Response {
     let lines = ctx.requester.get_lines().await;
     Json(lines).into_response()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use background::common::start_in;
+    use cassis::{
+        test_utils::{hop, secret, signed_transfer, trust},
+        Transfer, Trust,
+    };
+
+    // 1 can send 100 to the registry and 2 can send 50
+    async fn context(path: &std::path::Path) -> Arc<GlobalContext> {
+        let requester = start_in(path, 1000);
+        for op in [trust(0, 1, 100), trust(0, 2, 50)] {
+            requester.append_operation(op).await.unwrap();
+        }
+        Arc::new(GlobalContext { requester })
+    }
+
+    async fn parts(resp: axum::response::Response) -> (StatusCode, serde_json::Value) {
+        let status = resp.status();
+        let body = axum::body::to_bytes(resp.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[tokio::test]
+    async fn rejected_operations_get_the_status_of_what_is_wrong() {
+        let dir = tempfile::tempdir().unwrap();
+        let ctx = context(dir.path()).await;
+        let unsigned = |hops| {
+            Operation::Transfer(Transfer {
+                ts: 0,
+                hops,
+                sigs: vec![],
+            })
+        };
+
+        for (op, status, error) in [
+            (
+                Operation::Unknown,
+                StatusCode::BAD_REQUEST,
+                "unknown_operation",
+            ),
+            (unsigned(vec![]), StatusCode::BAD_REQUEST, "empty_transfer"),
+            (
+                unsigned(vec![hop(1, 0, 0)]),
+                StatusCode::BAD_REQUEST,
+                "zero_amount_hop",
+            ),
+            (
+                unsigned(
+                    (0..128)
+                        .flat_map(|_| [hop(1, 0, 1), hop(0, 1, 1)])
+                        .collect(),
+                ),
+                StatusCode::BAD_REQUEST,
+                "too_many_hops",
+            ),
+            (trust(7, 1, 10), StatusCode::NOT_FOUND, "unknown_key"),
+            (
+                signed_transfer(vec![hop(1, 2, 10)]),
+                StatusCode::NOT_FOUND,
+                "no_line",
+            ),
+            (
+                trust(1, 1, 10),
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "self_trust",
+            ),
+            (
+                signed_transfer(vec![hop(1, 0, 101)]),
+                StatusCode::CONFLICT,
+                "insufficient_credit",
+            ),
+            (
+                unsigned(vec![hop(1, 0, 10)]),
+                StatusCode::FORBIDDEN,
+                "missing_signer",
+            ),
+            (
+                Operation::Trust(Trust::new(secret(2), 1, secret(2).public(), 10)),
+                StatusCode::FORBIDDEN,
+                "bad_signature",
+            ),
+        ] {
+            let resp = append_op(axum::extract::State(ctx.clone()), axum::extract::Json(op)).await;
+            let (got, body) = parts(resp).await;
+            assert_eq!(
+                (got, body["error"].as_str()),
+                (status, Some(error)),
+                "{}",
+                body
+            );
+            assert!(body["message"].is_string());
+            assert!(body.get("index").is_none());
+        }
+
+        let resp = append_op(
+            axum::extract::State(ctx.clone()),
+            axum::extract::Json(signed_transfer(vec![hop(1, 0, 100)])),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn rejected_batches_say_which_operation_is_wrong() {
+        let dir = tempfile::tempdir().unwrap();
+        let ctx = context(dir.path()).await;
+
+        // the transfer goes through the line the trust before it opens, so it is checked against it
+        for (ops, index, status) in [
+            (
+                vec![trust(1, 2, 10), signed_transfer(vec![hop(2, 1, 11)])],
+                1,
+                StatusCode::CONFLICT,
+            ),
+            (
+                vec![trust(1, 1, 10), trust(0, 3, 10)],
+                0,
+                StatusCode::UNPROCESSABLE_ENTITY,
+            ),
+            (
+                vec![trust(0, 3, 10), trust(3, 1, 5), trust(9, 1, 5)],
+                2,
+                StatusCode::NOT_FOUND,
+            ),
+        ] {
+            let resp =
+                append_batch(axum::extract::State(ctx.clone()), axum::extract::Json(ops)).await;
+            let (got, body) = parts(resp).await;
+            assert_eq!(got, status, "{}", body);
+            assert_eq!(body["index"], index, "{}", body);
+        }
+    }
+
+    #[tokio::test]
+    async fn batches_over_the_limit_are_not_appended() {
+        let dir = tempfile::tempdir().unwrap();
+        let ctx = context(dir.path()).await;
+
+        let ops = vec![trust(1, 2, 10); background::MAX_BATCH_OPS + 1];
+        let resp = append_batch(axum::extract::State(ctx.clone()), axum::extract::Json(ops)).await;
+        assert_eq!(resp.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(ctx.requester.list(None, 10).await.unwrap().entries.len(), 2);
+
+        // up to it they are fine, even if it is the same trust over and over
+        let ops = vec![trust(1, 2, 10); background::MAX_BATCH_OPS];
+        let resp = append_batch(axum::extract::State(ctx.clone()), axum::extract::Json(ops)).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+}
@@ -0,0 +1,39 @@
+// fixtures shared by the tests in this crate, on top of the ones in `cassis::test_utils`
+use cassis::{
+    test_utils::{apply, keys, trust},
+    Operation, State,
+};
+
+// `keys(nkeys)` with these trusts, given as (from, to, amount), so `to` can send up to `amount` to
+// `from`
+pub fn with_trusts(nkeys: u32, trusts: &[(u32, u32, u32)]) -> State {
+    let mut state = keys(nkeys);
+    for (from, to, amount) in trusts {
+        apply(&mut state, trust(*from, *to, *amount));
+    }
+    state
+}
+
+// an empty database with all the tables, removed with the directory
+pub fn database() -> (tempfile::TempDir, redb::Database) {
+    let dir = tempfile::tempdir().unwrap();
+    let db = redb::Database::create(dir.path().join("router.redb")).unwrap();
+    crate::db::ensure_tables(&db);
+    (dir, db)
+}
+
+// the operations as the registry log would give them, starting from its first entry
+pub fn entries(ops: &[Operation]) -> Vec<cassis::log::Entry> {
+    let mut previous = cassis::log::GENESIS;
+    ops.iter()
+        .enumerate()
+        .map(|(idx, op)| {
+            previous = cassis::log::chain_hash(&previous, &cassis::log::entry_hash(op));
+            cassis::log::Entry {
+                idx: idx as u32,
+                hash: previous,
+                op: op.clone(),
+            }
+        })
+        .collect()
+}
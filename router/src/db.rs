@@ -9,10 +9,24 @@ lazy_static! {
 
 pub const LINES: TableDefinition<u64, Line> = TableDefinition::new("lines");
 
-pub fn ensure_tables() {
-    let txn = DB.begin_write().unwrap();
+// every key after the registry's own (which is always at index 0)
+pub const KEYS: TableDefinition<u32, [u8; 32]> = TableDefinition::new("keys");
+
+// bookkeeping, currently just the serial of the next operation we expect from the registry
+pub const META: TableDefinition<&str, u32> = TableDefinition::new("meta");
+pub const OP_SERIAL: &str = "op_serial";
+
+// the chain hash of the last operation we applied, which the next one has to follow
+pub const HASHES: TableDefinition<&str, [u8; 32]> = TableDefinition::new("hashes");
+pub const CHAIN_HASH: &str = "chain_hash";
+
+pub fn ensure_tables(db: &Database) {
+    let txn = db.begin_write().unwrap();
     {
         let _ = txn.open_table(LINES);
+        let _ = txn.open_table(KEYS);
+        let _ = txn.open_table(META);
+        let _ = txn.open_table(HASHES);
     }
     txn.commit().unwrap();
 }
@@ -0,0 +1,185 @@
+use anyhow::Context;
+use redb::Database;
+use std::{
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use crate::state;
+
+// follows the registry log forever, applying every operation to our state as it comes and
+// reconnecting from the last persisted serial whenever the stream breaks
+pub async fn follow(db: &Database, registry: String, state: Arc<RwLock<cassis::State>>) {
+    let client = reqwest::Client::new();
+    loop {
+        match stream_log(db, &client, &registry, &state).await {
+            Ok(()) => tracing::warn!("registry closed the log stream; reconnecting"),
+            Err(err) => tracing::warn!("failed to follow registry log: {:#}; reconnecting", err),
+        }
+        tokio::time::sleep(Duration::from_secs(5)).await;
+    }
+}
+
+async fn stream_log(
+    db: &Database,
+    client: &reqwest::Client,
+    registry: &str,
+    state: &RwLock<cassis::State>,
+) -> Result<(), anyhow::Error> {
+    let mut position = Position::load(db)?;
+
+    let mut req = client
+        .get(format!("{}/log", registry))
+        .query(&[("live", "true")]);
+    if let Some(after) = position.after() {
+        req = req.query(&[("after", after)]);
+    }
+    let mut response = req.send().await?.error_for_status()?;
+    tracing::info!("following registry log from {}", position.serial);
+
+    // the log comes as newline-delimited json, which may be split anywhere between chunks
+    let mut buf: Vec<u8> = Vec::with_capacity(4096);
+    while let Some(chunk) = response.chunk().await? {
+        buf.extend_from_slice(&chunk);
+
+        while let Some(end) = buf.iter().position(|b| *b == b'\n') {
+            let line: Vec<u8> = buf.drain(..=end).collect();
+            if line.iter().all(|b| b.is_ascii_whitespace()) {
+                continue;
+            }
+
+            let entry: cassis::log::Entry = serde_json::from_slice(&line)
+                .with_context(|| format!("invalid operation at serial {}", position.serial))?;
+            position.apply(db, state, &entry)?;
+        }
+    }
+
+    Ok(())
+}
+
+// how far into the registry log we are, as persisted with the state
+struct Position {
+    // the index of the next entry we expect
+    serial: u32,
+    // the chain hash of the last entry we applied, if we know it
+    previous: Option<[u8; 32]>,
+}
+
+impl Position {
+    fn load(db: &Database) -> Result<Self, anyhow::Error> {
+        Ok(Position {
+            serial: state::op_serial(db)?,
+            previous: state::chain_hash(db)?,
+        })
+    }
+
+    // what to ask the registry for so it starts with the next entry
+    fn after(&self) -> Option<u32> {
+        self.serial.checked_sub(1)
+    }
+
+    // applies `entry` if it is the one that comes next, anything else means we missed or repeated
+    // something, or the registry changed what it had already given us
+    fn apply(
+        &mut self,
+        db: &Database,
+        state: &RwLock<cassis::State>,
+        entry: &cassis::log::Entry,
+    ) -> Result<(), anyhow::Error> {
+        if entry.idx != self.serial {
+            return Err(anyhow::anyhow!(
+                "expected operation {}, got {}",
+                self.serial,
+                entry.idx
+            ));
+        }
+        if self
+            .previous
+            .is_some_and(|previous| !entry.follows(&previous))
+        {
+            return Err(anyhow::anyhow!(
+                "operation {} doesn't follow the chain we have",
+                self.serial
+            ));
+        }
+
+        state::apply(db, state, entry)
+            .with_context(|| format!("failed to apply operation {}", self.serial))?;
+        self.previous = Some(entry.hash);
+        self.serial += 1;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::{database, entries};
+    use cassis::test_utils::{secret, trust};
+
+    fn ops() -> Vec<cassis::Operation> {
+        vec![
+            trust(0, 1, 100),
+            trust(0, 2, 50),
+            trust(1, 2, 30),
+            trust(0, 1, 120),
+        ]
+    }
+
+    #[test]
+    fn follows_the_log_and_resumes_from_what_it_persisted() {
+        let (_dir, db) = database();
+        let entries = entries(&ops());
+        let state = state::init(&db, secret(0).public()).unwrap();
+
+        let mut position = Position::load(&db).unwrap();
+        assert_eq!(position.after(), None);
+        assert_eq!(position.previous, Some(cassis::log::GENESIS));
+        for entry in entries[..2].iter() {
+            position.apply(&db, &state, entry).unwrap();
+        }
+
+        // as if the router was restarted
+        let mut position = Position::load(&db).unwrap();
+        assert_eq!(position.serial, 2);
+        assert_eq!(position.after(), Some(1));
+        assert_eq!(position.previous, Some(entries[1].hash));
+        let restarted = state::init(&db, secret(0).public()).unwrap();
+        assert_eq!(*restarted.read().unwrap(), *state.read().unwrap());
+
+        for entry in entries[2..].iter() {
+            position.apply(&db, &restarted, entry).unwrap();
+        }
+        let reloaded = state::init(&db, secret(0).public()).unwrap();
+        assert_eq!(*reloaded.read().unwrap(), *restarted.read().unwrap());
+        assert_eq!(reloaded.read().unwrap().keys.len(), 3);
+    }
+
+    #[test]
+    fn entries_out_of_place_or_off_the_chain_are_not_applied() {
+        let (_dir, db) = database();
+        let entries = entries(&ops());
+        let state = state::init(&db, secret(0).public()).unwrap();
+        let mut position = Position::load(&db).unwrap();
+        for entry in entries[..2].iter() {
+            position.apply(&db, &state, entry).unwrap();
+        }
+        let before = state.read().unwrap().clone();
+
+        // skipped, repeated, or changed after the registry gave it to us
+        let mut changed = entries[2].clone();
+        changed.op = trust(1, 2, 31);
+        let mut forked = entries[2].clone();
+        forked.hash[0] ^= 1;
+        for entry in [&entries[3], &entries[1], &changed, &forked] {
+            assert!(position.apply(&db, &state, entry).is_err());
+        }
+
+        assert_eq!(*state.read().unwrap(), before);
+        assert_eq!(position.serial, 2);
+        assert_eq!(state::op_serial(&db).unwrap(), 2);
+        assert_eq!(state::chain_hash(&db).unwrap(), Some(entries[1].hash));
+
+        position.apply(&db, &state, &entries[2]).unwrap();
+    }
+}
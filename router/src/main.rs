@@ -1,31 +1,122 @@
 use axum::{
     http::status::StatusCode,
     response::{IntoResponse, Json},
-    routing::{get, post},
+    routing::get,
 };
-use cassis::operation::Operation;
-use db::DB;
 use lazy_static::lazy_static;
-use state::{process, validate, State};
 use std::{
-    cmp::min,
     env,
     sync::{Arc, RwLock},
 };
 
+#[cfg(test)]
+mod common;
 mod db;
+mod follower;
+mod routing;
 mod state;
 
+// the most alternative routes we'll compute for a single request
+const MAX_ROUTES: usize = 10;
+
+lazy_static! {
+    static ref REGISTRY_KEY: cassis::PublicKey = {
+        let hexkey = env::var("REGISTRY_KEY").unwrap_or(
+            "46d44c5e71dbbb5b59d97e1aa887d9bdd05ed052178a0b588f99d089e61dfd20".to_string(),
+        );
+        cassis::PublicKey::from_hex(&hexkey).expect("invalid REGISTRY_KEY")
+    };
+}
+
 #[tokio::main]
 async fn main() {
-    db::ensure_tables();
+    let subscriber = tracing_subscriber::fmt()
+        .compact()
+        .with_file(true)
+        .with_line_number(true)
+        .with_thread_ids(true)
+        .with_target(true)
+        .finish();
+    tracing::subscriber::set_global_default(subscriber).unwrap();
 
-    let state = state::init().expect("failed to init state from db");
+    db::ensure_tables(&db::DB);
+
+    let state = state::init(&db::DB, *REGISTRY_KEY).expect("failed to init state from db");
     let shared_state = Arc::new(state);
 
-    let app = axum::Router::new().route("/", get(|| async { "cassis-router" }));
+    let registry = env::var("REGISTRY_URL").unwrap_or("http://localhost:6000".to_string());
+    tokio::spawn(follower::follow(&db::DB, registry, shared_state.clone()));
+
+    let app = axum::Router::new()
+        .route("/", get(|| async { "cassis-router" }))
+        .route("/route", get(get_route))
+        .route("/split", get(get_split))
+        .with_state(shared_state);
 
-    println!("listening on http://localhost:7000",);
-    let listener = tokio::net::TcpListener::bind("0.0.0.0:6000").await.unwrap();
+    println!("listening on http://localhost:7000");
+    let listener = tokio::net::TcpListener::bind("0.0.0.0:7000").await.unwrap();
     axum::serve(listener, app).await.unwrap();
 }
+
+#[derive(serde::Deserialize)]
+struct GetRouteParams {
+    from: u32,
+    to: u32,
+    amount: u32,
+    max: Option<usize>,
+}
+
+async fn get_route(
+    axum::extract::State(state): axum::extract::State<Arc<RwLock<cassis::State>>>,
+    axum::extract::Query(qs): axum::extract::Query<GetRouteParams>,
+) -> axum::response::Response {
+    let routes = {
+        let state = state.read().expect("state lock poisoned");
+        routing::find_routes(
+            &state,
+            qs.from,
+            qs.to,
+            qs.amount,
+            qs.max.unwrap_or(3).min(MAX_ROUTES),
+        )
+    };
+
+    if routes.is_empty() {
+        (StatusCode::NOT_FOUND, "no route found").into_response()
+    } else {
+        Json(routes).into_response()
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct GetSplitParams {
+    from: u32,
+    to: u32,
+    amount: u32,
+}
+
+#[derive(serde::Serialize)]
+struct Split {
+    // each of the routes and how much goes through it
+    paths: Vec<Vec<cassis::Hop>>,
+    // all of the above merged into the hops of a single transfer
+    hops: Vec<cassis::Hop>,
+}
+
+async fn get_split(
+    axum::extract::State(state): axum::extract::State<Arc<RwLock<cassis::State>>>,
+    axum::extract::Query(qs): axum::extract::Query<GetSplitParams>,
+) -> axum::response::Response {
+    let paths = {
+        let state = state.read().expect("state lock poisoned");
+        routing::split_payment(&state, qs.from, qs.to, qs.amount)
+    };
+
+    match paths {
+        Some(paths) => match cassis::Transfer::merge_paths(&paths) {
+            Ok(hops) => Json(Split { paths, hops }).into_response(),
+            Err(err) => (StatusCode::UNPROCESSABLE_ENTITY, err.to_string()).into_response(),
+        },
+        None => (StatusCode::NOT_FOUND, "not enough capacity to reach payee").into_response(),
+    }
+}
@@ -0,0 +1,415 @@
+use cassis::{state::Line, Hop, State};
+use std::collections::{HashMap, HashSet, VecDeque};
+
+// a directed view of the ledger: each line becomes up to two edges, one for each direction
+// in which there is still credit available
+pub struct Graph {
+    edges: HashMap<u32, Vec<Edge>>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Edge {
+    to: u32,
+    capacity: i64,
+}
+
+impl Graph {
+    pub fn build(state: &State) -> Self {
+        let mut edges: HashMap<u32, Vec<Edge>> = HashMap::with_capacity(state.keys.len());
+        for line in state.lines.values() {
+            for (from, to) in [(line.peers.0, line.peers.1), (line.peers.1, line.peers.0)] {
+                // same rule as cassis::state::validate
+                let capacity = line.can_send(from);
+                if capacity > 0 {
+                    edges.entry(from).or_default().push(Edge { to, capacity });
+                }
+            }
+        }
+
+        // lines come from a hashmap, sort them so routes are deterministic
+        for list in edges.values_mut() {
+            list.sort_by_key(|edge| edge.to);
+        }
+
+        Graph { edges }
+    }
+
+    // breadth-first search for the path with the fewest hops in which every edge can carry `amount`,
+    // not going through any of the lines in `excluded`
+    fn shortest_path(
+        &self,
+        from: u32,
+        to: u32,
+        amount: i64,
+        excluded: &HashSet<u64>,
+    ) -> Option<Vec<u32>> {
+        let mut parents: HashMap<u32, u32> = HashMap::new();
+        let mut queue = VecDeque::from([from]);
+
+        while let Some(current) = queue.pop_front() {
+            if current == to {
+                let mut path = vec![to];
+                let mut node = to;
+                while node != from {
+                    node = parents[&node];
+                    path.push(node);
+                }
+                path.reverse();
+                return Some(path);
+            }
+
+            for edge in self.edges.get(&current).into_iter().flatten() {
+                if edge.capacity < amount
+                    || edge.to == from
+                    || parents.contains_key(&edge.to)
+                    || excluded.contains(&Line::build_key(current, edge.to))
+                {
+                    continue;
+                }
+                parents.insert(edge.to, current);
+                queue.push_back(edge.to);
+            }
+        }
+
+        None
+    }
+}
+
+// finds up to `max` alternative routes from `from` to `to`, each of them able to carry the full
+// `amount` on its own. routes don't share any line, so they stay valid if another one is used.
+pub fn find_routes(state: &State, from: u32, to: u32, amount: u32, max: usize) -> Vec<Vec<Hop>> {
+    if from == to || amount == 0 {
+        return vec![];
+    }
+
+    let graph = Graph::build(state);
+    let mut excluded: HashSet<u64> = HashSet::new();
+    let mut routes = Vec::with_capacity(max);
+
+    while routes.len() < max {
+        let Some(path) = graph.shortest_path(from, to, amount as i64, &excluded) else {
+            break;
+        };
+
+        let hops: Vec<Hop> = path
+            .windows(2)
+            .map(|pair| Hop {
+                from: pair[0],
+                to: pair[1],
+                amount,
+            })
+            .collect();
+        for hop in hops.iter() {
+            excluded.insert(Line::build_key(hop.from, hop.to));
+        }
+
+        routes.push(hops);
+    }
+
+    routes
+}
+
+// splits `amount` across as many routes as needed when no single one can carry it all.
+// this is a max-flow (edmonds-karp) over the lines in which each line is modelled by its two
+// directions: sending through it in one direction frees the same amount in the other, so the
+// residual graph is just the `can_send` of each side. returns `None` if the payee can't be
+// reached with the full amount.
+pub fn split_payment(state: &State, from: u32, to: u32, amount: u32) -> Option<Vec<Vec<Hop>>> {
+    if from == to || amount == 0 {
+        return None;
+    }
+
+    let mut residual: HashMap<(u32, u32), i64> = HashMap::with_capacity(state.lines.len() * 2);
+    let mut neighbors: HashMap<u32, Vec<u32>> = HashMap::with_capacity(state.keys.len());
+    for line in state.lines.values() {
+        let (a, b) = line.peers;
+        residual.insert((a, b), line.can_send(a).max(0));
+        residual.insert((b, a), line.can_send(b).max(0));
+        neighbors.entry(a).or_default().push(b);
+        neighbors.entry(b).or_default().push(a);
+    }
+    for list in neighbors.values_mut() {
+        list.sort();
+    }
+    let initial = residual.clone();
+
+    // augment along shortest paths until the amount is covered
+    let mut remaining = amount as i64;
+    while remaining > 0 {
+        let mut parents: HashMap<u32, u32> = HashMap::new();
+        let mut queue = VecDeque::from([from]);
+        while let Some(current) = queue.pop_front() {
+            if current == to {
+                break;
+            }
+            for &next in neighbors.get(&current).into_iter().flatten() {
+                if next == from || parents.contains_key(&next) || residual[&(current, next)] <= 0 {
+                    continue;
+                }
+                parents.insert(next, current);
+                queue.push_back(next);
+            }
+        }
+
+        if !parents.contains_key(&to) {
+            return None;
+        }
+
+        let mut bottleneck = remaining;
+        let mut node = to;
+        while node != from {
+            let parent = parents[&node];
+            bottleneck = bottleneck.min(residual[&(parent, node)]);
+            node = parent;
+        }
+
+        let mut node = to;
+        while node != from {
+            let parent = parents[&node];
+            *residual.get_mut(&(parent, node)).unwrap() -= bottleneck;
+            *residual.get_mut(&(node, parent)).unwrap() += bottleneck;
+            node = parent;
+        }
+
+        remaining -= bottleneck;
+    }
+
+    // the net flow on each line in the direction in which it was used
+    let mut flow: HashMap<u32, Vec<(u32, i64)>> = HashMap::new();
+    for (&(a, b), &cap) in initial.iter() {
+        let used = cap - residual[&(a, b)];
+        if used > 0 {
+            flow.entry(a).or_default().push((b, used));
+        }
+    }
+    for list in flow.values_mut() {
+        list.sort();
+    }
+
+    // decompose the flow into paths from payer to payee, cancelling any cycles on the way
+    let mut paths: Vec<Vec<Hop>> = Vec::new();
+    let mut remaining = amount as i64;
+    while remaining > 0 {
+        let mut path = vec![from];
+        while let Some(&current) = path.last() {
+            if current == to {
+                break;
+            }
+            let (next, _) = *flow[&current]
+                .iter()
+                .find(|(_, amount)| *amount > 0)
+                .expect("flow is conserved on every intermediate peer");
+
+            if let Some(start) = path.iter().position(|&peer| peer == next) {
+                // found a cycle, take it out of the flow and keep walking from where it started
+                let cycle: Vec<u32> = path[start..].iter().copied().chain([next]).collect();
+                let least = cycle
+                    .windows(2)
+                    .map(|pair| flow_between(&flow, pair[0], pair[1]))
+                    .min()
+                    .unwrap();
+                for pair in cycle.windows(2) {
+                    take_flow(&mut flow, pair[0], pair[1], least);
+                }
+                path.truncate(start + 1);
+                continue;
+            }
+
+            path.push(next);
+        }
+
+        let carried = path
+            .windows(2)
+            .map(|pair| flow_between(&flow, pair[0], pair[1]))
+            .min()
+            .unwrap()
+            .min(remaining);
+        for pair in path.windows(2) {
+            take_flow(&mut flow, pair[0], pair[1], carried);
+        }
+
+        paths.push(
+            path.windows(2)
+                .map(|pair| Hop {
+                    from: pair[0],
+                    to: pair[1],
+                    amount: carried as u32,
+                })
+                .collect(),
+        );
+        remaining -= carried;
+    }
+
+    Some(paths)
+}
+
+fn flow_between(flow: &HashMap<u32, Vec<(u32, i64)>>, from: u32, to: u32) -> i64 {
+    flow[&from]
+        .iter()
+        .find(|(peer, _)| *peer == to)
+        .map(|(_, amount)| *amount)
+        .unwrap_or(0)
+}
+
+fn take_flow(flow: &mut HashMap<u32, Vec<(u32, i64)>>, from: u32, to: u32, amount: i64) {
+    if let Some((_, current)) = flow
+        .get_mut(&from)
+        .and_then(|list| list.iter_mut().find(|(peer, _)| *peer == to))
+    {
+        *current -= amount;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::with_trusts;
+    use cassis::{
+        test_utils::{hop, signed_transfer},
+        Transfer,
+    };
+
+    #[test]
+    fn direct_route() {
+        let state = with_trusts(2, &[(0, 1, 100)]);
+        assert_eq!(find_routes(&state, 1, 0, 50, 5), [vec![hop(1, 0, 50)]]);
+    }
+
+    #[test]
+    fn route_through_other_peers() {
+        // 3 can only reach 0 through 2 and then 1
+        let state = with_trusts(4, &[(0, 1, 100), (1, 2, 100), (2, 3, 100)]);
+        assert_eq!(
+            find_routes(&state, 3, 0, 60, 5),
+            [vec![hop(3, 2, 60), hop(2, 1, 60), hop(1, 0, 60)]]
+        );
+    }
+
+    #[test]
+    fn no_route() {
+        let state = with_trusts(4, &[(0, 1, 100), (2, 3, 100)]);
+        // not connected at all
+        assert!(find_routes(&state, 3, 0, 10, 5).is_empty());
+        // connected, but the credit goes the other way
+        assert!(find_routes(&state, 0, 1, 10, 5).is_empty());
+        // nothing to route
+        assert!(find_routes(&state, 1, 1, 10, 5).is_empty());
+        assert!(find_routes(&state, 1, 0, 0, 5).is_empty());
+    }
+
+    #[test]
+    fn capacity_can_be_used_up_exactly() {
+        let state = with_trusts(3, &[(0, 1, 100), (1, 2, 100)]);
+        assert_eq!(find_routes(&state, 2, 0, 100, 5).len(), 1);
+        assert!(find_routes(&state, 2, 0, 101, 5).is_empty());
+    }
+
+    #[test]
+    fn max_limits_the_alternatives() {
+        // 4 can reach 0 directly, and through each of 1, 2 and 3
+        let state = with_trusts(
+            5,
+            &[
+                (0, 4, 100),
+                (0, 1, 100),
+                (1, 4, 100),
+                (0, 2, 100),
+                (2, 4, 100),
+                (0, 3, 100),
+                (3, 4, 100),
+            ],
+        );
+
+        let all = find_routes(&state, 4, 0, 10, 10);
+        assert_eq!(all.len(), 4);
+        // shortest first, and no two share a line
+        assert_eq!(all[0], [hop(4, 0, 10)]);
+        let mut lines: Vec<u64> = all
+            .iter()
+            .flatten()
+            .map(|hop| Line::build_key(hop.from, hop.to))
+            .collect();
+        lines.sort();
+        lines.dedup();
+        assert_eq!(lines.len(), 7);
+
+        assert_eq!(find_routes(&state, 4, 0, 10, 2), all[..2]);
+        assert!(find_routes(&state, 4, 0, 10, 0).is_empty());
+    }
+
+    // every path goes from `from` to `to` carrying one amount, and all of them together don't
+    // use more of a line than it can send
+    fn check_split(state: &State, from: u32, to: u32, amount: u32, paths: &[Vec<Hop>]) {
+        let mut used: HashMap<(u32, u32), i64> = HashMap::new();
+        let mut total = 0;
+        for path in paths {
+            assert_eq!(path.first().unwrap().from, from);
+            assert_eq!(path.last().unwrap().to, to);
+            for pair in path.windows(2) {
+                assert_eq!(pair[0].to, pair[1].from);
+                assert_eq!(pair[0].amount, pair[1].amount);
+            }
+            for hop in path {
+                *used.entry((hop.from, hop.to)).or_default() += hop.amount as i64;
+            }
+            total += path[0].amount;
+        }
+        assert_eq!(total, amount);
+
+        for ((a, b), amount) in used {
+            let line = &state.lines[&Line::build_key(a, b)];
+            let back = used_between(paths, b, a);
+            assert!(amount - back <= line.can_send(a), "{} -> {}", a, b);
+        }
+    }
+
+    fn used_between(paths: &[Vec<Hop>], from: u32, to: u32) -> i64 {
+        paths
+            .iter()
+            .flatten()
+            .filter(|hop| hop.from == from && hop.to == to)
+            .map(|hop| hop.amount as i64)
+            .sum()
+    }
+
+    #[test]
+    fn split_adds_up_and_stays_within_each_line() {
+        // 3 can reach 0 through 1 or 2, with 60 on each side
+        let state = with_trusts(4, &[(0, 1, 60), (1, 3, 60), (0, 2, 60), (2, 3, 60)]);
+        assert!(find_routes(&state, 3, 0, 100, 5).is_empty());
+
+        let paths = split_payment(&state, 3, 0, 100).unwrap();
+        assert_eq!(paths.len(), 2);
+        check_split(&state, 3, 0, 100, &paths);
+
+        // a single route is enough when it can carry it all
+        let paths = split_payment(&state, 3, 0, 60).unwrap();
+        check_split(&state, 3, 0, 60, &paths);
+    }
+
+    #[test]
+    fn split_paths_sharing_a_line_merge_into_a_valid_transfer() {
+        // everything from 4 goes through 3, which can pay 0 directly or through 1
+        let state = with_trusts(5, &[(3, 4, 100), (0, 3, 50), (1, 3, 50), (0, 1, 50)]);
+
+        let paths = split_payment(&state, 4, 0, 80).unwrap();
+        check_split(&state, 4, 0, 80, &paths);
+        assert_eq!(used_between(&paths, 4, 3), 80);
+        assert!(paths.len() > 1);
+
+        let hops = Transfer::merge_paths(&paths).unwrap();
+        assert_eq!(hops.iter().filter(|hop| hop.from == 4).count(), 1);
+
+        let op = signed_transfer(hops);
+        assert_eq!(cassis::state::validate(&state, &op), Ok(()));
+    }
+
+    #[test]
+    fn split_is_none_without_enough_capacity() {
+        let state = with_trusts(4, &[(0, 1, 60), (1, 3, 60), (0, 2, 60), (2, 3, 60)]);
+        assert!(split_payment(&state, 3, 0, 120).is_some());
+        assert!(split_payment(&state, 3, 0, 121).is_none());
+        assert!(split_payment(&state, 0, 3, 1).is_none());
+        assert!(split_payment(&state, 3, 3, 1).is_none());
+    }
+}
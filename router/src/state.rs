@@ -1,27 +1,185 @@
-use anyhow::anyhow;
+use anyhow::{anyhow, Context};
+use redb::ReadableTable;
 use std::{collections::HashMap, hash::BuildHasherDefault, sync::RwLock};
 
-pub fn init(initial_key: cassis::PublicKey) -> Result<RwLock<cassis::State>, anyhow::Error> {
+use redb::Database;
+
+use crate::db::{CHAIN_HASH, HASHES, KEYS, LINES, META, OP_SERIAL};
+
+pub fn init(
+    db: &Database,
+    initial_key: cassis::PublicKey,
+) -> Result<RwLock<cassis::State>, anyhow::Error> {
     let mut state = cassis::State {
         keys: vec![initial_key],
         key_indexes: HashMap::with_capacity(500),
         lines: HashMap::with_capacity_and_hasher(1000, BuildHasherDefault::default()),
-        op_serial: 0,
     };
 
     state.key_indexes.insert(initial_key.serialize(), 0);
 
-    for (i, row) in LOG.iter().enumerate() {
-        let (key, operation) = row.with_context(|| format!("at row index {}", i))?;
+    let txn = db.begin_read()?;
+
+    let keys = txn.open_table(KEYS)?;
+    for (i, row) in keys.iter()?.enumerate() {
+        let (idx, key) = row.with_context(|| format!("at key row index {}", i))?;
 
-        let serial = key.value();
-        if i as u64 != serial {
-            return Err(anyhow!("row index ({}) != serial key ({})", i, serial));
+        let idx = idx.value();
+        if idx as usize != state.keys.len() {
+            return Err(anyhow!(
+                "key index ({}) != expected ({})",
+                idx,
+                state.keys.len()
+            ));
         }
-        state.op_serial = serial;
 
-        let op = operation.value();
-        cassis::state::process(&mut state, &op);
+        let key = key.value();
+        let pk = cassis::PublicKey::from_hex(&hex::encode(key))
+            .map_err(|err| anyhow!("invalid key at index {}: {}", idx, err))?;
+        state.keys.push(pk);
+        state.key_indexes.insert(key, idx);
+    }
+
+    let lines = txn.open_table(LINES)?;
+    for (i, row) in lines.iter()?.enumerate() {
+        let (key, line) = row.with_context(|| format!("at line row index {}", i))?;
+        state.lines.insert(key.value(), line.value());
     }
+
     Ok(RwLock::new(state))
 }
+
+// the serial of the next operation we have to fetch from the registry
+pub fn op_serial(db: &Database) -> Result<u32, anyhow::Error> {
+    let txn = db.begin_read()?;
+    let meta = txn.open_table(META)?;
+    Ok(meta.get(OP_SERIAL)?.map(|v| v.value()).unwrap_or(0))
+}
+
+// the chain hash of the last operation we applied, `GENESIS` before the first one. none if we
+// applied some before keeping it, then the next one is taken as it comes.
+pub fn chain_hash(db: &Database) -> Result<Option<[u8; 32]>, anyhow::Error> {
+    let txn = db.begin_read()?;
+    let hashes = txn.open_table(HASHES)?;
+    match hashes.get(CHAIN_HASH)? {
+        Some(hash) => Ok(Some(hash.value())),
+        None if op_serial(db)? == 0 => Ok(Some(cassis::log::GENESIS)),
+        None => Ok(None),
+    }
+}
+
+// the lines an operation changes in `state`, as far as it knows the keys in it
+fn touched_lines(state: &cassis::State, op: &cassis::Operation) -> Vec<u64> {
+    match op {
+        cassis::Operation::Unknown => vec![],
+        cassis::Operation::Trust(t) => state
+            .key_indexes
+            .get(&t.to.serialize())
+            .map(|to| cassis::state::Line::build_key(t.from, *to))
+            .into_iter()
+            .collect(),
+        cassis::Operation::Transfer(t) => t
+            .hops
+            .iter()
+            .map(|hop| cassis::state::Line::build_key(hop.from, hop.to))
+            .collect(),
+    }
+}
+
+// applies an entry to the in-memory state and persists everything it touched, together with the
+// new serial and chain hash, in a single transaction so a restart never sees one without the other
+pub fn apply(
+    db: &Database,
+    state: &RwLock<cassis::State>,
+    entry: &cassis::log::Entry,
+) -> Result<(), anyhow::Error> {
+    apply_and_persist(state, &entry.op, |state, new_keys, touched| {
+        let txn = db.begin_write()?;
+        {
+            let mut keys = txn.open_table(KEYS)?;
+            for (idx, key) in state.keys.iter().enumerate().skip(new_keys) {
+                keys.insert(idx as u32, key.serialize())?;
+            }
+
+            let mut lines = txn.open_table(LINES)?;
+            for line_key in touched {
+                lines.insert(line_key, state.lines[line_key].clone())?;
+            }
+
+            let mut meta = txn.open_table(META)?;
+            meta.insert(OP_SERIAL, entry.idx + 1)?;
+
+            let mut hashes = txn.open_table(HASHES)?;
+            hashes.insert(CHAIN_HASH, entry.hash)?;
+        }
+        txn.commit()?;
+        Ok(())
+    })
+}
+
+// processes `op` and then calls `persist` with the new state, the index of the first key it added
+// and the lines it touched. if that fails the state is put back as it was, so memory never has an
+// operation that the database doesn't.
+fn apply_and_persist(
+    state: &RwLock<cassis::State>,
+    op: &cassis::Operation,
+    persist: impl FnOnce(&cassis::State, usize, &[u64]) -> Result<(), anyhow::Error>,
+) -> Result<(), anyhow::Error> {
+    let mut state = state.write().expect("state lock poisoned");
+
+    let previous_nkeys = state.keys.len();
+    let previous_lines: Vec<(u64, cassis::state::Line)> = touched_lines(&state, op)
+        .into_iter()
+        .filter_map(|key| state.lines.get(&key).map(|line| (key, line.clone())))
+        .collect();
+
+    cassis::state::process(&mut state, op);
+    let touched = touched_lines(&state, op);
+
+    if let Err(err) = persist(&state, previous_nkeys, &touched) {
+        for key in touched {
+            state.lines.remove(&key);
+        }
+        state.lines.extend(previous_lines);
+        let added: Vec<cassis::PublicKey> = state.keys.drain(previous_nkeys..).collect();
+        for key in added {
+            state.key_indexes.remove(&key.serialize());
+        }
+        return Err(err);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::{database, entries};
+    use cassis::test_utils::{hop, secret, signed_transfer, trust};
+
+    #[test]
+    fn state_is_left_as_it_was_when_it_cant_be_persisted() {
+        let (_dir, db) = database();
+        let ops = vec![trust(0, 1, 100), trust(1, 2, 50)];
+        let state = init(&db, secret(0).public()).unwrap();
+        for entry in entries(&ops) {
+            apply(&db, &state, &entry).unwrap();
+        }
+        let before = state.read().unwrap().clone();
+
+        // a new key and line, a line that changes and a transfer
+        for op in [
+            trust(2, 3, 10),
+            trust(1, 2, 60),
+            signed_transfer(vec![hop(2, 1, 20), hop(1, 0, 20)]),
+        ] {
+            let failed = apply_and_persist(&state, &op, |_, _, _| Err(anyhow!("disk is full")));
+            assert!(failed.is_err());
+            assert_eq!(*state.read().unwrap(), before);
+        }
+
+        // and the database still has what it had
+        let reloaded = init(&db, secret(0).public()).unwrap();
+        assert_eq!(*reloaded.read().unwrap(), before);
+    }
+}